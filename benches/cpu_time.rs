@@ -4,18 +4,23 @@ use criterion::{Criterion, Bencher};
 
 use rendergraph::*;
 
+fn node(graph: &mut Graph, size: Size, deps: &[NodeId]) -> NodeId {
+    let task_id = TaskId::Render(0, graph.num_nodes() as u32);
+    graph.add_node(task_id, TargetKind::Color, size, AllocKind::Dynamic, deps)
+}
+
 fn graph_2() -> Graph {
     let mut graph = Graph::new();
 
-    let n0 = graph.add_node("n0", size2(100, 100), &[]);
-    let _ = graph.add_node("n00", size2(100, 100), &[n0]);
-    let n1 = graph.add_node("n1", size2(100, 100), &[]);
-    let n2 = graph.add_node("n2", size2(100, 100), &[]);
-    let n3 = graph.add_node("n3", size2(100, 100), &[n1, n2]);
-    let n4 = graph.add_node("n4", size2(100, 100), &[]);
-    let n5 = graph.add_node("n5", size2(100, 100), &[n2, n4]);
-    let n6 = graph.add_node("n6", size2(100, 100), &[n1, n2, n3, n4, n5]);
-    let n7 = graph.add_node("root", size2(800, 600), &[n6]);
+    let n0 = node(&mut graph, size2(100, 100), &[]);
+    let _ = node(&mut graph, size2(100, 100), &[n0]);
+    let n1 = node(&mut graph, size2(100, 100), &[]);
+    let n2 = node(&mut graph, size2(100, 100), &[]);
+    let n3 = node(&mut graph, size2(100, 100), &[n1, n2]);
+    let n4 = node(&mut graph, size2(100, 100), &[]);
+    let n5 = node(&mut graph, size2(100, 100), &[n2, n4]);
+    let n6 = node(&mut graph, size2(100, 100), &[n1, n2, n3, n4, n5]);
+    let n7 = node(&mut graph, size2(800, 600), &[n6]);
 
     graph.add_root(n7);
     graph.add_root(n4);
@@ -26,53 +31,53 @@ fn graph_2() -> Graph {
 fn graph_1() -> Graph {
     let mut graph = Graph::new();
 
-    let n0 = graph.add_node("n0", size2(100, 100), &[]);
-    let _ = graph.add_node("n00", size2(100, 100), &[n0]);
-    let n1 = graph.add_node("n1", size2(100, 100), &[]);
-    let n2 = graph.add_node("n2", size2(100, 100), &[]);
-    let n3 = graph.add_node("n3", size2(100, 100), &[n1, n2]);
-    let n4 = graph.add_node("n4", size2(100, 100), &[]);
-    let n5 = graph.add_node("n5", size2(100, 100), &[n2, n4]);
-    let n6 = graph.add_node("n6", size2(100, 100), &[n1, n2, n3, n4, n5]);
-    let n7 = graph.add_node("root", size2(800, 600), &[n6]);
+    let n0 = node(&mut graph, size2(100, 100), &[]);
+    let _ = node(&mut graph, size2(100, 100), &[n0]);
+    let n1 = node(&mut graph, size2(100, 100), &[]);
+    let n2 = node(&mut graph, size2(100, 100), &[]);
+    let n3 = node(&mut graph, size2(100, 100), &[n1, n2]);
+    let n4 = node(&mut graph, size2(100, 100), &[]);
+    let n5 = node(&mut graph, size2(100, 100), &[n2, n4]);
+    let n6 = node(&mut graph, size2(100, 100), &[n1, n2, n3, n4, n5]);
+    let n7 = node(&mut graph, size2(800, 600), &[n6]);
 
     graph.add_root(n7);
     graph.add_root(n4);
 
-    let n0 = graph.add_node("n0", size2(100, 100), &[]);
-    let _ = graph.add_node("n00", size2(100, 100), &[n0]);
-    let n1 = graph.add_node("n1", size2(100, 100), &[]);
-    let n2 = graph.add_node("n2", size2(100, 100), &[]);
-    let n3 = graph.add_node("n3", size2(100, 100), &[n1, n2]);
-    let n4 = graph.add_node("n4", size2(100, 100), &[]);
-    let n5 = graph.add_node("n5", size2(100, 100), &[n2, n4]);
-    let n6 = graph.add_node("n6", size2(100, 100), &[n1, n2, n3, n4, n5]);
-    let n8 = graph.add_node("n8", size2(800, 600), &[n6]);
+    let n0 = node(&mut graph, size2(100, 100), &[]);
+    let _ = node(&mut graph, size2(100, 100), &[n0]);
+    let n1 = node(&mut graph, size2(100, 100), &[]);
+    let n2 = node(&mut graph, size2(100, 100), &[]);
+    let n3 = node(&mut graph, size2(100, 100), &[n1, n2]);
+    let n4 = node(&mut graph, size2(100, 100), &[]);
+    let n5 = node(&mut graph, size2(100, 100), &[n2, n4]);
+    let n6 = node(&mut graph, size2(100, 100), &[n1, n2, n3, n4, n5]);
+    let n8 = node(&mut graph, size2(800, 600), &[n6]);
 
     graph.add_root(n8);
 
-    let n0 = graph.add_node("n0", size2(100, 100), &[]);
-    let _ = graph.add_node("n00", size2(100, 100), &[n0]);
-    let n1 = graph.add_node("n1", size2(100, 100), &[]);
-    let n2 = graph.add_node("n2", size2(100, 100), &[]);
-    let n3 = graph.add_node("n3", size2(100, 100), &[n1, n2]);
-    let n4 = graph.add_node("n4", size2(100, 100), &[]);
-    let n5 = graph.add_node("n5", size2(100, 100), &[n2, n4]);
-    let n6 = graph.add_node("n6", size2(100, 100), &[n1, n2, n3, n4, n5]);
-    let n9 = graph.add_node("n9", size2(800, 600), &[n6]);
-
-
-    let n0 = graph.add_node("n0", size2(100, 100), &[]);
-    let _ = graph.add_node("n00", size2(100, 100), &[n0]);
-    let n1 = graph.add_node("n1", size2(100, 100), &[]);
-    let n2 = graph.add_node("n2", size2(100, 100), &[]);
-    let n3 = graph.add_node("n3", size2(100, 100), &[n1, n2]);
-    let n4 = graph.add_node("n4", size2(100, 100), &[]);
-    let n5 = graph.add_node("n5", size2(100, 100), &[n2, n4]);
-    let n10 = graph.add_node("n6", size2(100, 100), &[n2, n4, n5]);
-
-    let n11 = graph.add_node("n11", size2(100, 100), &[n8, n9]);
-    let n12 = graph.add_node("n12", size2(100, 100), &[n11, n10]);
+    let n0 = node(&mut graph, size2(100, 100), &[]);
+    let _ = node(&mut graph, size2(100, 100), &[n0]);
+    let n1 = node(&mut graph, size2(100, 100), &[]);
+    let n2 = node(&mut graph, size2(100, 100), &[]);
+    let n3 = node(&mut graph, size2(100, 100), &[n1, n2]);
+    let n4 = node(&mut graph, size2(100, 100), &[]);
+    let n5 = node(&mut graph, size2(100, 100), &[n2, n4]);
+    let n6 = node(&mut graph, size2(100, 100), &[n1, n2, n3, n4, n5]);
+    let n9 = node(&mut graph, size2(800, 600), &[n6]);
+
+
+    let n0 = node(&mut graph, size2(100, 100), &[]);
+    let _ = node(&mut graph, size2(100, 100), &[n0]);
+    let n1 = node(&mut graph, size2(100, 100), &[]);
+    let n2 = node(&mut graph, size2(100, 100), &[]);
+    let _ = node(&mut graph, size2(100, 100), &[n1, n2]);
+    let n4 = node(&mut graph, size2(100, 100), &[]);
+    let n5 = node(&mut graph, size2(100, 100), &[n2, n4]);
+    let n10 = node(&mut graph, size2(100, 100), &[n2, n4, n5]);
+
+    let n11 = node(&mut graph, size2(100, 100), &[n8, n9]);
+    let n12 = node(&mut graph, size2(100, 100), &[n11, n10]);
 
     graph.add_root(n12);
 
@@ -83,7 +88,7 @@ fn graph_1() -> Graph {
 fn do_bench(
     c: &mut Criterion,
     name: &'static str,
-    graph_fn: &'static Fn() -> Graph,
+    graph_fn: &'static dyn Fn() -> Graph,
     options: BuilderOptions,
 ) {
     c.bench_function(
@@ -91,77 +96,50 @@ fn do_bench(
         move |b: &mut Bencher| {
             let graph = graph_fn();
 
-            let mut builder = GraphBuilder::new(options);
-
-            let mut allocator = GuillotineAllocator::new();
+            let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
 
             b.iter(|| {
                 allocator.textures.clear();
-                let _ = builder.build(graph.clone(), &mut allocator);
+                let _ = graph.clone().build(&options, &mut allocator);
             })
         }
     );
 }
 
-fn do_bench_no_allocator(
-    c: &mut Criterion,
-    name: &'static str,
-    graph_fn: &'static Fn() -> Graph,
-    options: BuilderOptions,
-) {
-    c.bench_function(
-        name,
-        move |b: &mut Bencher| {
-            let graph = graph_fn();
-
-            let mut builder = GraphBuilder::new(options);
-
-            let mut allocator = DummyTextureAllocator::new();
-
-            b.iter(|| {
-                let _ = builder.build(graph.clone(), &mut allocator);
-            })
-        }
-    );
-}
-
-fn culled_eager_pingpong_guillotine(c: &mut Criterion) {
-    do_bench(c, "culled_eager_pingpong_guillotine",
+fn pingpong_guillotine(c: &mut Criterion) {
+    do_bench(c, "pingpong_guillotine",
         &graph_1,
         BuilderOptions {
-            culling: true,
-            passes: PassOptions::Eager,
             targets: TargetOptions::PingPong,
+            .. BuilderOptions::default()
         },
     )
 }
 
-fn culled_eager_direct_guillotine(c: &mut Criterion) {
-    do_bench(c, "culled_eager_direct_guillotine",
+fn direct_guillotine(c: &mut Criterion) {
+    do_bench(c, "direct_guillotine",
         &graph_1,
         BuilderOptions {
-            culling: true,
-            passes: PassOptions::Eager,
             targets: TargetOptions::Direct,
+            .. BuilderOptions::default()
         },
     )
 }
 
-fn culled_eager_pingpong_no_allocator(c: &mut Criterion) {
-    do_bench_no_allocator(c, "culled_eager_pingpong_no_allocator",
-        &graph_1,
+fn direct_guillotine_small(c: &mut Criterion) {
+    do_bench(c, "direct_guillotine_small",
+        &graph_2,
         BuilderOptions {
-            culling: true,
-            passes: PassOptions::Eager,
-            targets: TargetOptions::PingPong,
+            targets: TargetOptions::Direct,
+            .. BuilderOptions::default()
         },
     )
 }
 
 criterion_group!(benches,
-    culled_eager_pingpong_guillotine,
-    culled_eager_direct_guillotine,
-    culled_eager_pingpong_no_allocator,
+    pingpong_guillotine,
+    direct_guillotine,
+    direct_guillotine_small,
 );
 
 criterion_main!(benches);
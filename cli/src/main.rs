@@ -9,7 +9,6 @@ use clap::*;
 
 use std::io::prelude::*;
 use std::fs::{File, OpenOptions};
-use std::collections::HashMap;
 
 fn main() {
     let matches = App::new("Render graph command-line interface")
@@ -62,6 +61,20 @@ fn main() {
                 .takes_value(false)
                 .required(false)
             )
+            .arg(Arg::with_name("PLANNED")
+                .long("planned")
+                .help("Plan the allocations for the whole graph instead of pass by pass.")
+                .value_name("PLANNED")
+                .takes_value(false)
+                .required(false)
+            )
+            .arg(Arg::with_name("DEALLOCATE_FIRST")
+                .long("deallocate-first")
+                .help("Free inputs living in other textures before allocating a pass's outputs.")
+                .value_name("DEALLOCATE_FIRST")
+                .takes_value(false)
+                .required(false)
+            )
             .arg(Arg::with_name("GRAPH")
                 .short("g")
                 .long("graph")
@@ -205,6 +218,61 @@ fn main() {
                 .takes_value(true)
                 .required(false)
             )
+            .arg(Arg::with_name("ATLAS_TIMELINE")
+                .long("atlas-timeline")
+                .help("Show the allocations of each texture over the passes instead of the graph.")
+                .value_name("ATLAS_TIMELINE")
+                .takes_value(false)
+                .required(false)
+            )
+            .arg(Arg::with_name("LIGHT")
+                .long("light")
+                .help("Use a light background.")
+                .value_name("LIGHT")
+                .takes_value(false)
+                .required(false)
+            )
+            .arg(Arg::with_name("COMPACT")
+                .long("compact")
+                .help("Only show the labels of the nodes, in smaller boxes.")
+                .value_name("COMPACT")
+                .takes_value(false)
+                .required(false)
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("html")
+            .about("Dump the graph as an interactive HTML page")
+            .arg(Arg::with_name("GRAPH")
+                .short("-a")
+                .long("graph")
+                .help("Input graph file.")
+                .value_name("GRAPH")
+                .takes_value(true)
+             )
+            .arg(Arg::with_name("HTML_OUTPUT")
+                .help("Output HTML file to use")
+                .value_name("FILE")
+                .takes_value(true)
+                .required(false)
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("trace")
+            .about("Dump the graph in the Chrome trace event format")
+            .arg(Arg::with_name("GRAPH")
+                .short("-a")
+                .long("graph")
+                .help("Input graph file.")
+                .value_name("GRAPH")
+                .takes_value(true)
+             )
+            .arg(Arg::with_name("TRACE_OUTPUT")
+                .help("Output JSON file to use")
+                .value_name("FILE")
+                .takes_value(true)
+                .required(false)
+            )
         )
         .subcommand(
             SubCommand::with_name("list")
@@ -231,6 +299,10 @@ fn main() {
         write_graph(&session, cmd);
     } else if let Some(cmd) = matches.subcommand_matches("svg") {
         svg(cmd);
+    } else if let Some(cmd) = matches.subcommand_matches("html") {
+        html(cmd);
+    } else if let Some(cmd) = matches.subcommand_matches("trace") {
+        trace(cmd);
     } else if let Some(cmd) = matches.subcommand_matches("list") {
         list(cmd);
     }
@@ -240,14 +312,20 @@ fn main() {
 pub struct Session {
     graph: Graph,
     built_graph: Option<BuiltGraph>,
-    names: HashMap<String, NodeId>,
     allocator_options: AllocatorOptions,
     builder_options: BuilderOptions,
     default_size: Size,
     next_name: i32,
     next_task_id: u32,
+    /// Keeps the textures of the previous builds, so that each command builds the graph
+    /// like a new frame would.
+    #[serde(default)]
+    texture_pool: Option<TexturePool>,
 }
 
+/// Number of builds after which an unused texture is removed from the session's pool.
+const MAX_UNUSED_FRAMES: u64 = 4;
+
 fn init(args: &ArgMatches) {
     let w = args.value_of("WIDTH").map(|s| s.parse::<i32>().unwrap()).unwrap_or(1024);
     let h = args.value_of("HEIGHT").map(|s| s.parse::<i32>().unwrap()).unwrap_or(1024);
@@ -272,20 +350,26 @@ fn init(args: &ArgMatches) {
         } else {
             TargetOptions::Direct
         },
+        allocations: if args.is_present("PLANNED") {
+            AllocationStrategy::Planned
+        } else {
+            AllocationStrategy::PassByPass
+        },
+        deallocate_before_allocate: args.is_present("DEALLOCATE_FIRST"),
     };
 
     let session = Session {
         graph: Graph::new(),
         built_graph: None,
-        names: std::collections::HashMap::default(),
         allocator_options,
         builder_options,
         default_size: size2(w, h),
         next_name: 0,
         next_task_id: 0,
+        texture_pool: None,
     };
 
-    write_graph(&session, &args);
+    write_graph(&session, args);
 
     if args.is_present("SVG_OUTPUT") {
         svg(args);
@@ -293,8 +377,19 @@ fn init(args: &ArgMatches) {
 }
 
 fn build(session: &mut Session) {
-    let mut allocator = GuillotineAllocator::with_options(session.default_size, &session.allocator_options);
-    session.built_graph = Some(session.graph.clone().build(&session.builder_options, &mut allocator));
+    session.built_graph = Some(build_graph(session));
+}
+
+/// Build the graph with the textures of the session's pool.
+fn build_graph(session: &mut Session) -> BuiltGraph {
+    let default_size = session.default_size;
+    let allocator_options = session.allocator_options;
+    let pool = session.texture_pool.get_or_insert_with(|| {
+        TexturePool::with_options(default_size, MAX_UNUSED_FRAMES, &allocator_options)
+    });
+    pool.begin_frame();
+
+    session.graph.clone().build(&session.builder_options, pool).expect("Failed to build the graph.")
 }
 
 fn node(args: &ArgMatches) {
@@ -303,7 +398,7 @@ fn node(args: &ArgMatches) {
     let mut inputs = Vec::new();
     if let Some(names) = args.values_of("INPUT") {
         for name in names {
-            inputs.push(session.names[name]);
+            inputs.push(find_node(&session.graph, name));
         }
     }
 
@@ -327,6 +422,7 @@ fn node(args: &ArgMatches) {
 
     let id = session.graph.add_node(TaskId::Render(0, session.next_task_id), target_kind, size2(w, h), alloc_kind, &inputs[..]);
     session.next_task_id += 1;
+    session.graph.set_label(id, name.clone());
 
     if args.is_present("ROOT") {
         session.graph.add_root(id);
@@ -334,8 +430,6 @@ fn node(args: &ArgMatches) {
 
     println!("Added node {}", name);
 
-    session.names.insert(name, id);
-
     build(&mut session);
 
     write_graph(&session, args);
@@ -348,10 +442,9 @@ fn node(args: &ArgMatches) {
 fn root(args: &ArgMatches) {
     let mut session = load_graph(args);
 
-    let name = args.value_of("NAME").unwrap().to_string();
-    let id = session.names.get(&name).expect("Couldn't find node with this name.");
+    let id = find_node(&session.graph, args.value_of("NAME").unwrap());
 
-    session.graph.add_root(*id);
+    session.graph.add_root(id);
 
     build(&mut session);
 
@@ -363,61 +456,91 @@ fn root(args: &ArgMatches) {
 }
 
 fn svg(args: &ArgMatches) {
-    let session = load_graph(args);
+    let mut session = load_graph(args);
 
     let svg_file_name = args.value_of("SVG_OUTPUT").unwrap_or("rendergraph.svg");
     let mut svg_file = File::create(svg_file_name).expect(
         "Failed to open the SVG file."
     );
 
-    let mut allocator = GuillotineAllocator::with_options(session.default_size, &session.allocator_options);
-    let built_graph = session.graph.clone().build(&session.builder_options, &mut allocator);
+    let built_graph = build_graph(&mut session);
 
-    let lookup_name = &|node_id| {
-        for (name, id) in &session.names {
-            if *id == node_id {
-                return name.as_str();
-            }
+    if args.is_present("ATLAS_TIMELINE") {
+        rendergraph::dump_atlas_timeline(&mut svg_file, &built_graph);
+    } else {
+        let mut options = if args.is_present("COMPACT") {
+            SvgOptions::compact()
+        } else {
+            SvgOptions::default()
+        };
+        if args.is_present("LIGHT") {
+            options.theme = SvgTheme::light();
         }
-        return &"";
-    };
+        rendergraph::dump_svg_with_options(&mut svg_file, &built_graph, &options);
+    }
 
-    rendergraph::dump_svg(&mut svg_file, &built_graph, &allocator, Some(lookup_name));
+    write_graph(&session, args);
+}
+
+fn html(args: &ArgMatches) {
+    let mut session = load_graph(args);
+
+    let html_file_name = args.value_of("HTML_OUTPUT").unwrap_or("rendergraph.html");
+    let mut html_file = File::create(html_file_name).expect(
+        "Failed to open the HTML file."
+    );
+
+    let built_graph = build_graph(&mut session);
+
+    rendergraph::dump_html(&mut html_file, &built_graph);
+
+    write_graph(&session, args);
+}
+
+fn trace(args: &ArgMatches) {
+    let mut session = load_graph(args);
+
+    let trace_file_name = args.value_of("TRACE_OUTPUT").unwrap_or("rendergraph.json");
+    let mut trace_file = File::create(trace_file_name).expect(
+        "Failed to open the trace file."
+    );
+
+    let built_graph = build_graph(&mut session);
+
+    built_graph.write_chrome_trace(&mut trace_file);
+
+    write_graph(&session, args);
 }
 
 fn list(args: &ArgMatches) {
     let session = load_graph(args);
 
     println!("# Nodes");
-    for (name, id) in &session.names {
-        let mut deps = String::new();
-        for dep_id in session.graph.node_dependencies(*id) {
-            for (name, id) in &session.names {
-                if *id == *dep_id {
-                    deps += &format!("{}, ", name);
-                    break;
-                }
-            }
-        }
-        // Remove the trailing comma.
-        let deps = if deps.len() > 2 {
-            &deps[0..deps.len()-2]
-        } else {
-            ""
-        };
-        println!(" - {} ({})", name, deps);
+    for id in session.graph.node_ids() {
+        let deps: Vec<String> = session.graph.node_dependencies(id)
+            .iter()
+            .map(|&dep| session.graph.node_name(dep))
+            .collect();
+        println!(" - {} ({})", session.graph.node_name(id), deps.join(", "));
     }
     println!("# Roots");
     for &root in session.graph.roots() {
-        for (name, id) in &session.names {
-            if *id == root {
-                println!(" - {}", name);
-                continue;
-            }
-        }
+        println!(" - {}", session.graph.node_name(root));
+    }
+    if let Some(pool) = &session.texture_pool {
+        let stats = pool.stats();
+        println!("# Texture pool");
+        println!(" - {} textures", pool.textures.len());
+        println!(" - {} reused, {} created, {} evicted", stats.hits, stats.misses, stats.evictions);
     }
 }
 
+fn find_node(graph: &Graph, name: &str) -> NodeId {
+    graph.node_ids()
+        .find(|&id| graph.label(id) == Some(name))
+        .expect("Couldn't find node with this name.")
+}
+
 fn load_graph(args: &ArgMatches) -> Session {
     let file_name = args.value_of("GRAPH").unwrap_or("rendergraph.ron");
     let file = OpenOptions::new().read(true).open(file_name).expect(
@@ -1,20 +1,43 @@
-use std::usize;
-use std::collections::HashSet;
-use crate::{Size, Rectangle};
+use std::collections::{HashSet, HashMap};
+use crate::{Size, Rectangle, TargetKind};
 
 pub use guillotiere::{AtlasAllocator, Allocation, AllocId as RectangleId, AllocatorOptions};
 
+/// Identifies a texture.
+///
+/// Ids of dynamic textures are assigned by the graph builder from a reserved namespace
+/// (the most significant bit is set) so that they never collide with the ids of the
+/// fixed textures chosen by the user.
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 #[repr(transparent)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct TextureId(pub u64);
 
+const DYNAMIC_TEXTURE_BIT: u64 = 1 << 63;
+
 impl TextureId {
-    pub fn index(self) -> usize { self.0 as usize }
+    /// Returns the id of the nth dynamic texture.
+    pub fn dynamic(index: usize) -> Self {
+        TextureId(index as u64 | DYNAMIC_TEXTURE_BIT)
+    }
+
+    /// Whether this id is in the namespace reserved for dynamic textures.
+    pub fn is_dynamic(self) -> bool {
+        self.0 & DYNAMIC_TEXTURE_BIT != 0
+    }
+
+    /// The id without the dynamic namespace bit.
+    pub fn index(self) -> usize { (self.0 & !DYNAMIC_TEXTURE_BIT) as usize }
 }
 
-pub(crate) fn texture_id(idx: usize) -> TextureId {
-    TextureId(idx as u64)
+impl std::fmt::Debug for TextureId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.is_dynamic() {
+            write!(f, "TextureId(dyn {})", self.index())
+        } else {
+            write!(f, "TextureId({})", self.0)
+        }
+    }
 }
 
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
@@ -117,9 +140,59 @@ impl TextureArray {
 }
 
 pub trait TextureAllocator {
-    fn add_texture(&mut self) -> TextureId;
+    /// Create the texture `id`, or reset it if it already exists.
+    ///
+    /// The ids are provided by the graph builder, see `TextureId::dynamic`. `kind` is the
+    /// kind of the targets rendered into the texture, so that allocators can keep
+    /// separate textures per kind. Allocators that don't need it can ignore it.
+    fn add_texture(&mut self, id: TextureId, kind: TargetKind);
     fn allocate(&mut self, tex: TextureId, size: Size) -> AllocatedRectangle;
     fn deallocate(&mut self, id: AllocId);
+
+    /// The current size of a texture, if the allocator knows it.
+    ///
+    /// The graph builder records the sizes of the dynamic textures once all allocations
+    /// are done, see `BuiltGraph::texture_size`.
+    fn texture_size(&self, _id: TextureId) -> Option<Size> {
+        None
+    }
+
+    /// Split the allocator into one independent allocator per texture, so that
+    /// textures can be allocated from in parallel.
+    ///
+    /// Returns `None` if the allocator can't be split, in which case allocations are
+    /// performed serially.
+    fn texture_shards(&mut self) -> Option<Vec<AtlasShard<'_>>> {
+        None
+    }
+}
+
+/// Allocates from the atlas of a single texture borrowed from another allocator.
+///
+/// See `TextureAllocator::texture_shards`.
+pub struct AtlasShard<'l> {
+    pub texture: TextureId,
+    pub atlas: &'l mut AtlasAllocator,
+}
+
+impl<'l> TextureAllocator for AtlasShard<'l> {
+    fn add_texture(&mut self, _id: TextureId, _kind: TargetKind) {
+        panic!("Can't add textures to a texture shard.");
+    }
+
+    fn allocate(&mut self, texture_id: TextureId, size: Size) -> AllocatedRectangle {
+        assert_eq!(texture_id, self.texture);
+        allocate_growing(self.atlas, texture_id, size)
+    }
+
+    fn deallocate(&mut self, id: AllocId) {
+        assert_eq!(id.texture, self.texture);
+        self.atlas.deallocate(id.rectangle);
+    }
+
+    fn texture_size(&self, id: TextureId) -> Option<Size> {
+        if id == self.texture { Some(self.atlas.size()) } else { None }
+    }
 }
 
 pub struct GuillotineAllocator {
@@ -141,44 +214,232 @@ impl GuillotineAllocator {
         GuillotineAllocator {
             textures: Vec::new(),
             size,
-            options: options.clone(),
+            options: *options,
         }
     }
 }
 
 impl TextureAllocator for GuillotineAllocator {
 
-    fn add_texture(&mut self) -> TextureId {
-        self.textures.push(AtlasAllocator::with_options(self.size, &self.options));
-        texture_id(self.textures.len() - 1)
+    fn add_texture(&mut self, id: TextureId, _kind: TargetKind) {
+        let atlas = AtlasAllocator::with_options(self.size, &self.options);
+        if id.index() < self.textures.len() {
+            self.textures[id.index()] = atlas;
+        } else {
+            while self.textures.len() < id.index() {
+                self.textures.push(AtlasAllocator::with_options(self.size, &self.options));
+            }
+            self.textures.push(atlas);
+        }
     }
 
     fn allocate(&mut self, texture_id: TextureId, size: Size) -> AllocatedRectangle {
-        let atlas = &mut self.textures[texture_id.index()];
-        loop {
-            if let Some(alloc) = atlas.allocate(size) {
-                return AllocatedRectangle {
-                    rectangle: alloc.rectangle,
-                    id: AllocId {
-                        texture: texture_id,
-                        rectangle: alloc.id,
-                        slice: 0,
-                    }
+        allocate_growing(&mut self.textures[texture_id.index()], texture_id, size)
+    }
+
+    fn deallocate(&mut self, id: AllocId) {
+        self.textures[id.texture.index()].deallocate(id.rectangle);
+    }
+
+    fn texture_size(&self, id: TextureId) -> Option<Size> {
+        self.textures.get(id.index()).map(|atlas| atlas.size())
+    }
+
+    fn texture_shards(&mut self) -> Option<Vec<AtlasShard<'_>>> {
+        Some(self.textures.iter_mut().enumerate().map(|(idx, atlas)| AtlasShard {
+            texture: TextureId::dynamic(idx),
+            atlas,
+        }).collect())
+    }
+}
+
+/// Allocate a rectangle, doubling the size of the atlas until it fits.
+fn allocate_growing(atlas: &mut AtlasAllocator, texture_id: TextureId, size: Size) -> AllocatedRectangle {
+    loop {
+        if let Some(alloc) = atlas.allocate(size) {
+            return AllocatedRectangle {
+                rectangle: alloc.rectangle,
+                id: AllocId {
+                    texture: texture_id,
+                    rectangle: alloc.id,
+                    slice: 0,
+                }
+            }
+        }
+        let new_size = atlas.size() * 2;
+        atlas.grow(new_size);
+    }
+}
+
+/// A texture kept alive by a `TexturePool` across builds.
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct PooledTexture {
+    pub atlas: AtlasAllocator,
+    pub kind: TargetKind,
+    /// Stable identifier of the texture for as long as it stays in the pool, for example
+    /// to associate it with a GPU texture.
+    pub handle: u64,
+    /// The last frame this texture was used in.
+    pub last_used: u64,
+    in_use: bool,
+}
+
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TexturePoolStats {
+    /// Number of textures requested by a build that were served with a pooled texture.
+    pub hits: u64,
+    /// Number of textures requested by a build that required creating a new texture.
+    pub misses: u64,
+    /// Number of textures removed from the pool after being unused for too long.
+    pub evictions: u64,
+}
+
+/// A texture allocator that keeps its textures across builds.
+///
+/// Call `begin_frame` before each build. Textures from previous builds are handed back
+/// (with their atlas cleared) when the builder requests a texture of the same kind and
+/// size, and textures that haven't been used for more than `max_unused_frames` frames
+/// are evicted.
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct TexturePool {
+    pub textures: Vec<PooledTexture>,
+    pub size: Size,
+    pub options: AllocatorOptions,
+    pub max_unused_frames: u64,
+    bindings: HashMap<TextureId, usize>,
+    frame: u64,
+    next_handle: u64,
+    stats: TexturePoolStats,
+}
+
+impl TexturePool {
+    pub fn new(size: Size, max_unused_frames: u64) -> Self {
+        TexturePool::with_options(size, max_unused_frames, &guillotiere::DEFAULT_OPTIONS)
+    }
+
+    pub fn with_options(size: Size, max_unused_frames: u64, options: &AllocatorOptions) -> Self {
+        TexturePool {
+            textures: Vec::new(),
+            size,
+            options: *options,
+            max_unused_frames,
+            bindings: HashMap::new(),
+            frame: 0,
+            next_handle: 0,
+            stats: TexturePoolStats::default(),
+        }
+    }
+
+    /// Release the textures used by the previous build and evict the textures that
+    /// haven't been used recently.
+    pub fn begin_frame(&mut self) {
+        self.frame += 1;
+        self.bindings.clear();
+
+        let frame = self.frame;
+        let max_unused_frames = self.max_unused_frames;
+        let count = self.textures.len();
+        self.textures.retain(|texture| frame - texture.last_used <= max_unused_frames);
+        self.stats.evictions += (count - self.textures.len()) as u64;
+
+        for texture in &mut self.textures {
+            texture.in_use = false;
+        }
+    }
+
+    /// The pooled texture currently bound to a texture id of the graph.
+    pub fn pooled_texture(&self, id: TextureId) -> Option<&PooledTexture> {
+        self.bindings.get(&id).map(|&idx| &self.textures[idx])
+    }
+
+    pub fn stats(&self) -> TexturePoolStats {
+        self.stats
+    }
+}
+
+impl TextureAllocator for TexturePool {
+    fn add_texture(&mut self, id: TextureId, kind: TargetKind) {
+        if let Some(&idx) = self.bindings.get(&id) {
+            let texture = &mut self.textures[idx];
+            texture.atlas = AtlasAllocator::with_options(texture.atlas.size(), &self.options);
+            return;
+        }
+
+        // Look for the smallest available texture of the same kind that is at least as
+        // large as the default size.
+        let size = self.size;
+        let mut candidate: Option<usize> = None;
+        for (idx, texture) in self.textures.iter().enumerate() {
+            let texture_size = texture.atlas.size();
+            if texture.in_use
+                || texture.kind != kind
+                || texture_size.width < size.width
+                || texture_size.height < size.height {
+                continue;
+            }
+            if let Some(best) = candidate {
+                if self.textures[best].atlas.size().area() <= texture_size.area() {
+                    continue;
                 }
             }
-            let new_size = atlas.size() * 2;
-            atlas.grow(new_size);
+            candidate = Some(idx);
         }
+
+        let idx = match candidate {
+            Some(idx) => {
+                self.stats.hits += 1;
+                let texture = &mut self.textures[idx];
+                texture.atlas = AtlasAllocator::with_options(texture.atlas.size(), &self.options);
+                idx
+            }
+            None => {
+                self.stats.misses += 1;
+                self.textures.push(PooledTexture {
+                    atlas: AtlasAllocator::with_options(size, &self.options),
+                    kind,
+                    handle: self.next_handle,
+                    last_used: self.frame,
+                    in_use: false,
+                });
+                self.next_handle += 1;
+                self.textures.len() - 1
+            }
+        };
+
+        let texture = &mut self.textures[idx];
+        texture.in_use = true;
+        texture.last_used = self.frame;
+        self.bindings.insert(id, idx);
+    }
+
+    fn allocate(&mut self, texture_id: TextureId, size: Size) -> AllocatedRectangle {
+        let idx = self.bindings[&texture_id];
+        allocate_growing(&mut self.textures[idx].atlas, texture_id, size)
     }
 
     fn deallocate(&mut self, id: AllocId) {
-        self.textures[id.texture.index()].deallocate(id.rectangle);
+        let idx = self.bindings[&id.texture];
+        self.textures[idx].atlas.deallocate(id.rectangle);
+    }
+
+    fn texture_size(&self, id: TextureId) -> Option<Size> {
+        self.pooled_texture(id).map(|texture| texture.atlas.size())
+    }
+
+    fn texture_shards(&mut self) -> Option<Vec<AtlasShard<'_>>> {
+        let mut textures: Vec<Option<&mut PooledTexture>> = self.textures.iter_mut().map(Some).collect();
+        Some(self.bindings.iter().map(|(&texture, &idx)| AtlasShard {
+            texture,
+            atlas: &mut textures[idx].take().unwrap().atlas,
+        }).collect())
     }
 }
 
 pub struct DbgTextureAllocator<'l> {
     pub allocator: &'l mut dyn TextureAllocator,
     pub textures: Vec<HashSet<Rectangle>>,
+    pub allocations: HashMap<AllocId, Rectangle>,
     pub max_pixels: i32,
     pub max_rects: usize,
     pub record_deallocations: bool,
@@ -189,6 +450,7 @@ impl<'l> DbgTextureAllocator<'l> {
         DbgTextureAllocator {
             allocator,
             textures: Vec::new(),
+            allocations: HashMap::new(),
             max_pixels: 0,
             max_rects: 0,
             record_deallocations: true,
@@ -201,15 +463,19 @@ impl<'l> DbgTextureAllocator<'l> {
 }
 
 impl<'l> TextureAllocator for DbgTextureAllocator<'l> {
-    fn add_texture(&mut self) -> TextureId {
-        self.textures.push(HashSet::new());
-        self.allocator.add_texture()
+    fn add_texture(&mut self, id: TextureId, kind: TargetKind) {
+        if self.textures.len() <= id.index() {
+            self.textures.resize(id.index() + 1, HashSet::new());
+        }
+        self.textures[id.index()].clear();
+        self.allocator.add_texture(id, kind);
     }
 
     fn allocate(&mut self, texture_id: TextureId, size: Size) -> AllocatedRectangle {
         let alloc = self.allocator.allocate(texture_id, size);
 
         self.textures[texture_id.index()].insert(alloc.rectangle);
+        self.allocations.insert(alloc.id, alloc.rectangle);
 
         let mut pixels = 0;
         let mut rects = 0;
@@ -228,9 +494,54 @@ impl<'l> TextureAllocator for DbgTextureAllocator<'l> {
 
     fn deallocate(&mut self, id: AllocId) {
         if self.record_deallocations {
-            //self.textures[texture_id.index()].remove(&id);
+            if let Some(rect) = self.allocations.remove(&id) {
+                self.textures[id.texture.index()].remove(&rect);
+            }
             self.allocator.deallocate(id);
         }
     }
+
+    fn texture_size(&self, id: TextureId) -> Option<Size> {
+        self.allocator.texture_size(id)
+    }
 }
 
+
+#[test]
+fn texture_pool() {
+    use crate::{Graph, TaskId, AllocKind, BuilderOptions, TargetOptions};
+    use euclid::{size2, point2};
+
+    let mut graph = Graph::new();
+    let n0 = graph.add_node(TaskId::Render(0, 0), TargetKind::Alpha, size2(100, 100), AllocKind::Dynamic, &[]);
+    let n1 = graph.add_node(TaskId::Render(0, 1), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n0]);
+    let n2 = graph.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n1]);
+    let n3 = graph.add_node(TaskId::Render(0, 3), TargetKind::Color, size2(800, 600), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[n2]);
+    graph.add_root(n3);
+
+    let options = BuilderOptions { targets: TargetOptions::Direct, .. BuilderOptions::default() };
+    let mut pool = TexturePool::new(size2(512, 512), 2);
+
+    pool.begin_frame();
+    graph.clone().build(&options, &mut pool).unwrap();
+    assert_eq!(pool.stats(), TexturePoolStats { hits: 0, misses: 3, evictions: 0 });
+    let handles: Vec<u64> = (0..3).map(|i| pool.pooled_texture(TextureId::dynamic(i)).unwrap().handle).collect();
+
+    pool.begin_frame();
+    graph.clone().build(&options, &mut pool).unwrap();
+    assert_eq!(pool.stats(), TexturePoolStats { hits: 3, misses: 3, evictions: 0 });
+    let handles2: Vec<u64> = (0..3).map(|i| pool.pooled_texture(TextureId::dynamic(i)).unwrap().handle).collect();
+    assert_eq!(handles, handles2);
+
+    // Only uses one color texture.
+    let mut small_graph = Graph::new();
+    let n0 = small_graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    small_graph.add_root(n0);
+
+    for _ in 0..3 {
+        pool.begin_frame();
+        small_graph.clone().build(&options, &mut pool).unwrap();
+    }
+    assert_eq!(pool.stats(), TexturePoolStats { hits: 6, misses: 3, evictions: 2 });
+    assert_eq!(pool.textures.len(), 1);
+}
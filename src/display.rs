@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display, Formatter};
+use crate::graph::*;
+
+/// The name of an output, with the index of the output for additional outputs.
+fn output_name<T, U>(graph: &Graph<T, U>, output: NodeOutput) -> String {
+    match output.index {
+        0 => graph.node_name(output.node),
+        idx => format!("{}.{}", graph.node_name(output.node), idx),
+    }
+}
+
+/// The targets of a pass that have tasks, with a description of each of them.
+fn pass_targets<'a, T, U>(graph: &BuiltGraph<T, U>, pass: &'a Pass<T>, verbose: bool) -> Vec<(String, &'a PassTarget<T>)> {
+    let mut targets = Vec::new();
+    for (kind, target) in TARGET_KINDS.iter().zip(pass.dynamic_targets.iter()) {
+        if let Some(texture) = target.destination {
+            let mut name = format!("Dynamic {:?} target {:?}", kind, texture);
+            if let (true, Some(size)) = (verbose, graph.texture_size(texture)) {
+                name.push_str(&format!(" ({}x{})", size.width, size.height));
+            }
+            targets.push((name, target));
+        }
+    }
+    for target in &pass.fixed_targets {
+        targets.push((format!("Fixed target {:?}", target.destination.unwrap()), target));
+    }
+
+    targets
+}
+
+fn write_listing<T: Debug, U>(graph: &BuiltGraph<T, U>, f: &mut Formatter, verbose: bool) -> fmt::Result {
+    if verbose {
+        let num_tasks: usize = graph.passes().iter()
+            .flat_map(|pass| pass.dynamic_targets.iter().chain(pass.fixed_targets.iter()))
+            .map(|target| target.tasks.len())
+            .sum();
+        let memory = graph.memory_stats();
+        writeln!(f, "{} tasks, {} passes, {} inputs", num_tasks, graph.passes().len(), graph.inputs().len())?;
+        writeln!(
+            f, "{} dynamic textures, {} texture pixels, {} live pixels (max)",
+            memory.textures, memory.texture_pixels, memory.live_pixels,
+        )?;
+    }
+
+    let records: HashMap<NodeOutput, &AllocationRecord> = graph.allocations().iter()
+        .map(|record| (record.output, record))
+        .collect();
+
+    if !graph.inputs().is_empty() {
+        writeln!(f, "# inputs")?;
+        for &input in graph.inputs() {
+            let r = graph.allocated_rectangle(input);
+            writeln!(f, "     - {} {:?}      rect: [({}, {}) {}x{}]",
+                graph.node_name(input),
+                graph[input].alloc_kind,
+                r.min.x, r.min.y, r.size().width, r.size().height,
+            )?;
+        }
+    }
+
+    for (pass_index, pass) in graph.passes().iter().enumerate() {
+        writeln!(f, "# pass {:?}", pass_index)?;
+        for (name, target) in pass_targets(graph, pass, verbose) {
+            writeln!(f, "  * {}:", name)?;
+            for task in &target.tasks {
+                let output = NodeOutput::new(task.node_id, task.output);
+                let r = graph.output_rectangle(output);
+                writeln!(f, "     - {} {:?}      rect: [({}, {}) {}x{}]",
+                    output_name(graph, output),
+                    graph[task.node_id].task_id,
+                    r.min.x, r.min.y, r.size().width, r.size().height,
+                )?;
+
+                if !verbose {
+                    continue;
+                }
+
+                let inputs: Vec<String> = graph.node_inputs(task.node_id)
+                    .map(|input| output_name(graph, input))
+                    .collect();
+                if task.output == 0 && !inputs.is_empty() {
+                    writeln!(f, "         inputs: {}", inputs.join(", "))?;
+                }
+                if let Some(record) = records.get(&output) {
+                    match record.deallocated {
+                        Some(pass) => writeln!(f, "         freed before pass {}", pass)?,
+                        None => writeln!(f, "         kept until the end")?,
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Lists the passes, targets and tasks of the graph with the rectangles of the tasks.
+impl<T: Debug, U> Display for BuiltGraph<T, U> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write_listing(self, f, false)
+    }
+}
+
+/// Formats a built graph like its `Display` implementation, with memory statistics, the
+/// inputs of each task and when its allocation is freed. See `BuiltGraph::verbose`.
+pub struct VerboseDisplay<'a, T, U> {
+    graph: &'a BuiltGraph<T, U>,
+}
+
+impl<'a, T: Debug, U> Display for VerboseDisplay<'a, T, U> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write_listing(self.graph, f, true)
+    }
+}
+
+/// Formats a built graph in a concise format that only changes if the passes, targets or
+/// rectangles change. See `BuiltGraph::compact`.
+pub struct CompactDisplay<'a, T, U> {
+    graph: &'a BuiltGraph<T, U>,
+}
+
+impl<'a, T, U> Display for CompactDisplay<'a, T, U> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let graph = self.graph;
+        let name = |output: NodeOutput| match output.index {
+            0 => format!("n{}", output.node.index()),
+            idx => format!("n{}.{}", output.node.index(), idx),
+        };
+        let write_task = |f: &mut Formatter, output: NodeOutput| {
+            let r = graph.output_rectangle(output);
+            write!(f, " {}", name(output))?;
+            if graph.copy_nodes().contains(&output.node) {
+                let source = graph.node_inputs(output.node).next().unwrap();
+                write!(f, "=copy({})", name(source))?;
+            }
+            write!(f, "[{},{} {}x{}]", r.min.x, r.min.y, r.size().width, r.size().height)
+        };
+
+        if !graph.inputs().is_empty() {
+            write!(f, "inputs:")?;
+            for &input in graph.inputs() {
+                write_task(f, NodeOutput::main(input))?;
+            }
+            writeln!(f)?;
+        }
+
+        for (pass_index, pass) in graph.passes().iter().enumerate() {
+            writeln!(f, "pass {}:", pass_index)?;
+            let dynamic_targets = TARGET_KINDS.iter()
+                .zip(pass.dynamic_targets.iter())
+                .map(|(kind, target)| (format!("{:?}", kind), target));
+            let fixed_targets = pass.fixed_targets.iter()
+                .map(|target| ("Fixed".to_string(), target));
+            for (kind, target) in dynamic_targets.chain(fixed_targets) {
+                if target.tasks.is_empty() {
+                    continue;
+                }
+                write!(f, "  {} {:?}:", kind, target.destination.unwrap())?;
+                for task in &target.tasks {
+                    write_task(f, NodeOutput::new(task.node_id, task.output))?;
+                }
+                writeln!(f)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<T, U> BuiltGraph<T, U> {
+    /// A formatter that adds details to the output of `Display`.
+    pub fn verbose(&self) -> VerboseDisplay<'_, T, U> {
+        VerboseDisplay { graph: self }
+    }
+
+    /// A formatter with one line per target listing the node indices and rectangles of
+    /// its tasks, which doesn't depend on labels and task ids.
+    ///
+    /// Copy nodes added by the builder are followed by the output they copy, for
+    /// example `n9=copy(n3)`.
+    ///
+    /// This is useful to compare the results of builds, for example in tests.
+    pub fn compact(&self) -> CompactDisplay<'_, T, U> {
+        CompactDisplay { graph: self }
+    }
+}
+
+#[test]
+fn display_formats() {
+    use crate::{GuillotineAllocator, size2, point2};
+
+    let mut graph = Graph::new();
+    let n0 = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let mask = graph.add_output(n0, TargetKind::Alpha, size2(50, 50));
+    let n1 = graph.add_node(TaskId::Render(0, 1), TargetKind::Color, size2(200, 100), AllocKind::Dynamic, &[n0]);
+    graph.add_output_dependency(n1, mask);
+    let root = graph.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(400, 400), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[n1]);
+    graph.set_label(root, "root".to_string());
+    graph.add_root(root);
+
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    let built = graph.build(&BuilderOptions::default(), &mut allocator).unwrap();
+
+    assert_eq!(
+        built.compact().to_string(),
+        "pass 0:\n  \
+            Color TextureId(dyn 0): n0[0,0 100x100]\n  \
+            Alpha TextureId(dyn 1): n0.1[0,0 50x50]\n\
+        pass 1:\n  \
+            Color TextureId(dyn 2): n1[0,0 200x100]\n\
+        pass 2:\n  \
+            Fixed TextureId(0): n2[0,0 400x400]\n"
+    );
+
+    let listing = built.to_string();
+    assert!(listing.contains("# pass 1\n  * Dynamic Color target TextureId(dyn 2):\n     - NodeId(1) Render(0, 1)      rect: [(0, 0) 200x100]\n"));
+    assert!(listing.contains("  * Fixed target TextureId(0):\n     - root Render(0, 2)      rect: [(0, 0) 400x400]\n"));
+
+    let verbose = built.verbose().to_string();
+    assert!(verbose.starts_with("4 tasks, 3 passes, 0 inputs\n"));
+    assert!(verbose.contains("  * Dynamic Alpha target TextureId(dyn 1) (1024x1024):\n     - NodeId(0).1 Render(0, 0)      rect: [(0, 0) 50x50]\n         freed before pass 2\n"));
+    assert!(verbose.contains("         inputs: NodeId(0), NodeId(0).1\n         freed before pass 3\n"));
+}
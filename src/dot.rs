@@ -0,0 +1,202 @@
+use std::fmt::Debug;
+use std::io::Write;
+use crate::graph::*;
+
+/// Quote a string for DOT.
+fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+/// The DOT identifier of an output. Additional outputs have their own DOT node so
+/// that they can be shown in the target they are rendered into.
+fn dot_id(output: NodeOutput) -> String {
+    match output.index {
+        0 => format!("n{}", output.node.index()),
+        idx => format!("n{}_{}", output.node.index(), idx),
+    }
+}
+
+fn node_label<T: Debug, U>(graph: &Graph<T, U>, output: NodeOutput) -> String {
+    let node = &graph[output.node];
+    let size = node.output_size(output.index);
+    let name = match output.index {
+        0 => graph.node_name(output.node),
+        idx => format!("{}.{}", graph.node_name(output.node), idx),
+    };
+
+    quote(&format!("{}\n{:?}\n{}x{}", name, node.task_id, size.width, size.height))
+}
+
+fn write_header(output: &mut dyn Write) {
+    writeln!(output, "digraph rendergraph {{").unwrap();
+    writeln!(output, "    rankdir=LR;").unwrap();
+    writeln!(output, r##"    node [shape=box, style="rounded,filled", fillcolor="#c8c8c8", fontname="sans-serif"];"##).unwrap();
+}
+
+fn write_node<T: Debug, U>(output: &mut dyn Write, graph: &Graph<T, U>, node: NodeOutput, indent: &str, attributes: &str) {
+    writeln!(output, "{}{} [label={}{}];", indent, dot_id(node), node_label(graph, node), attributes).unwrap();
+}
+
+fn write_edges<T, U>(output: &mut dyn Write, graph: &Graph<T, U>, separate_outputs: bool, culled: &dyn Fn(NodeId) -> bool) {
+    for id in graph.node_ids() {
+        for input in graph.node_inputs(id) {
+            let mut attributes = Vec::new();
+            let from = if separate_outputs || input.index == 0 {
+                dot_id(input)
+            } else {
+                attributes.push(format!("label=\"output {}\"", input.index));
+                dot_id(NodeOutput::main(input.node))
+            };
+            if culled(id) {
+                attributes.push(r##"color="#a0a0a0""##.to_string());
+            }
+            let attributes = if attributes.is_empty() {
+                String::new()
+            } else {
+                format!(" [{}]", attributes.join(", "))
+            };
+            writeln!(output, "    {} -> {}{};", from, dot_id(NodeOutput::main(id)), attributes).unwrap();
+        }
+    }
+}
+
+impl<T: Debug, U> Graph<T, U> {
+    /// Write the graph in the Graphviz DOT format.
+    ///
+    /// Roots have a thicker border. Edges reading an additional output are labeled
+    /// with the index of the output.
+    pub fn write_dot(&self, output: &mut dyn Write) {
+        write_header(output);
+
+        for id in self.node_ids() {
+            let attributes = if self.roots().contains(&id) { ", penwidth=3" } else { "" };
+            write_node(output, self, NodeOutput::main(id), "    ", attributes);
+        }
+
+        write_edges(output, self, false, &|_| false);
+
+        writeln!(output, "}}").unwrap();
+    }
+}
+
+impl<T: Debug, U> BuiltGraph<T, U> {
+    /// Write the built graph in the Graphviz DOT format.
+    ///
+    /// Nodes are clustered by pass and by target. Copy nodes added by the builder are
+    /// highlighted and nodes that don't contribute to the roots are greyed out.
+    pub fn write_dot(&self, output: &mut dyn Write) {
+        write_header(output);
+
+        let mut scheduled = vec![false; self.num_nodes()];
+        let style = |output: NodeOutput| {
+            if self.copy_nodes().contains(&output.node) {
+                r##", fillcolor="#f0b060""##
+            } else if self.roots().contains(&output.node) && output.index == 0 {
+                ", penwidth=3"
+            } else {
+                ""
+            }
+        };
+
+        if !self.inputs().is_empty() {
+            writeln!(output, "    subgraph cluster_inputs {{").unwrap();
+            writeln!(output, "        label=\"Inputs\";").unwrap();
+            for &input in self.inputs() {
+                scheduled[input.index()] = true;
+                write_node(output, self, NodeOutput::main(input), "        ", r##", fillcolor="#a0c8f0""##);
+            }
+            writeln!(output, "    }}").unwrap();
+        }
+
+        for (pass_index, pass) in self.passes().iter().enumerate() {
+            writeln!(output, "    subgraph cluster_pass_{} {{", pass_index).unwrap();
+            writeln!(output, "        label=\"Pass {}\";", pass_index).unwrap();
+
+            let dynamic_targets = pass.dynamic_targets.iter()
+                .enumerate()
+                .map(|(kind, target)| (format!("dynamic_{}", kind), target));
+            let fixed_targets = pass.fixed_targets.iter()
+                .enumerate()
+                .map(|(idx, target)| (format!("fixed_{}", idx), target));
+            for (name, target) in dynamic_targets.chain(fixed_targets) {
+                if target.tasks.is_empty() {
+                    continue;
+                }
+                let kind = self[target.tasks[0].node_id].output_target_kind(target.tasks[0].output);
+                writeln!(output, "        subgraph cluster_pass_{}_{} {{", pass_index, name).unwrap();
+                writeln!(output, "            label={};", quote(&format!("{:?} - {:?}", kind, target.destination.unwrap()))).unwrap();
+                for task in &target.tasks {
+                    scheduled[task.node_id.index()] = true;
+                    let node = NodeOutput::new(task.node_id, task.output);
+                    write_node(output, self, node, "            ", style(node));
+                }
+                writeln!(output, "        }}").unwrap();
+            }
+
+            writeln!(output, "    }}").unwrap();
+        }
+
+        for id in self.node_ids() {
+            if !scheduled[id.index()] {
+                for idx in 0..self[id].num_outputs() {
+                    write_node(output, self, NodeOutput::new(id, idx as u32), "    ", r##", style="rounded,filled,dashed", fontcolor="#a0a0a0", color="#a0a0a0", fillcolor="#f0f0f0""##);
+                }
+            }
+        }
+
+        write_edges(output, self, true, &|id| !scheduled[id.index()]);
+
+        writeln!(output, "}}").unwrap();
+    }
+}
+
+#[test]
+fn dot_output() {
+    use crate::{GuillotineAllocator, size2, point2};
+
+    let mut graph = Graph::new();
+    let n0 = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let mask = graph.add_output(n0, TargetKind::Alpha, size2(50, 50));
+    let n1 = graph.add_node(TaskId::Render(0, 1), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n0]);
+    let n2 = graph.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n1, n0]);
+    graph.add_output_dependency(n2, mask);
+    let culled = graph.add_node(TaskId::Render(0, 3), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n0]);
+    let root = graph.add_node(TaskId::Render(0, 4), TargetKind::Color, size2(400, 400), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[n2]);
+    graph.set_label(root, "\"root\"".to_string());
+    graph.add_root(root);
+
+    let mut output = Vec::new();
+    graph.write_dot(&mut output);
+    let dot = String::from_utf8(output).unwrap();
+    assert!(dot.contains(r#"n4 [label="\"root\"\nRender(0, 4)\n400x400", penwidth=3];"#));
+    assert!(dot.contains(r#"n0 -> n2 [label="output 1"];"#));
+    assert!(dot.contains("n1 -> n2;"));
+
+    // The pass 0 color texture is read in pass 2 so ping-pong targets need a copy.
+    let options = BuilderOptions { targets: TargetOptions::PingPong, .. BuilderOptions::default() };
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    let built = graph.build(&options, &mut allocator).unwrap();
+    assert_eq!(built.copy_nodes().len(), 1);
+    let copy = built.copy_nodes()[0];
+
+    let mut output = Vec::new();
+    built.write_dot(&mut output);
+    let dot = String::from_utf8(output).unwrap();
+    assert!(dot.contains("subgraph cluster_pass_0_dynamic_1 {"));
+    assert!(dot.contains(r#"n0_1 [label="NodeId(0).1\nRender(0, 0)\n50x50"];"#));
+    assert!(dot.contains(&format!(r##"{} [label="NodeId({})\nCopy\n100x100", fillcolor="#f0b060"];"##, dot_id(NodeOutput::main(copy)), copy.index())));
+    assert!(dot.contains("n0_1 -> n2;"));
+    assert!(dot.contains(&format!(r#"{} [label="NodeId({})\nRender(0, 3)\n100x100", style="rounded,filled,dashed""#, dot_id(NodeOutput::main(culled)), culled.index())));
+}
@@ -1,11 +1,14 @@
-
-use std::i32;
-use smallvec::SmallVec;
+use smallvec::{SmallVec, smallvec};
+use crate::planner::{plan_target_rects, memory_stats, extents, MemoryStats};
 
 pub use guillotiere::{Rectangle, Size, Point};
 pub use euclid::{size2, vec2, point2};
 
 pub use crate::allocator::{TextureId, TextureAllocator, GuillotineAllocator, DbgTextureAllocator};
+use crate::allocator::{AllocId, TexturePool};
+#[cfg(feature = "rayon")]
+use crate::allocator::AtlasShard;
+use std::collections::HashMap;
 
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 #[repr(transparent)]
@@ -17,10 +20,42 @@ impl NodeId {
 }
 
 pub(crate) fn node_id(idx: usize) -> NodeId {
-    debug_assert!(idx < std::u32::MAX as usize);
+    debug_assert!(idx < u32::MAX as usize);
     NodeId(idx as u32)
 }
 
+/// One of the outputs of a node.
+///
+/// Output 0 is the main output of the node, described by its size, target kind and
+/// allocation kind. Other outputs are added with `add_output`.
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct NodeOutput {
+    pub node: NodeId,
+    pub index: u32,
+}
+
+impl NodeOutput {
+    pub fn new(node: NodeId, index: u32) -> Self {
+        NodeOutput { node, index }
+    }
+
+    pub fn main(node: NodeId) -> Self {
+        NodeOutput { node, index: 0 }
+    }
+}
+
+/// Describes an additional output of a node.
+///
+/// Additional outputs are always dynamically allocated, in the pass's render target of
+/// their kind, and use the allocation options of their node.
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct OutputDescriptor {
+    pub target_kind: TargetKind,
+    pub size: Size,
+}
+
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct NodeIdRange {
@@ -50,6 +85,11 @@ impl NodeIdRange {
         (self.end - self.start) as usize
     }
 
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+
     #[inline]
     pub fn get(&self, nth: usize) -> NodeId {
         assert!(nth < self.len());
@@ -64,12 +104,17 @@ pub enum TargetKind {
     Alpha = 1,
 }
 
-const NUM_TARGET_KINDS: usize = 2;
+pub(crate) const NUM_TARGET_KINDS: usize = 2;
+pub(crate) const TARGET_KINDS: [TargetKind; NUM_TARGET_KINDS] = [TargetKind::Color, TargetKind::Alpha];
 
-pub trait GraphBuilder {
+/// Common interface of the types nodes can be added to.
+///
+/// `T` is the type describing the task of each node and `U` the type of the user data
+/// nodes can carry.
+pub trait GraphBuilder<T = TaskId, U = ()> {
     fn add_node(
         &mut self,
-        task_id: TaskId,
+        task_id: T,
         target_kind: TargetKind,
         size: Size,
         alloc_kind: AllocKind,
@@ -79,16 +124,110 @@ pub trait GraphBuilder {
     fn add_dependency(&mut self, node: NodeId, dep: NodeId);
 
     fn add_root(&mut self, node: NodeId);
+
+    /// Make sure a node is rendered even if no root depends on it, for example because
+    /// it writes into a texture that is read back.
+    ///
+    /// Unlike roots, the allocation of the node is freed after it is read for the last
+    /// time, or right after its pass if nothing reads it.
+    fn add_side_effect(&mut self, node: NodeId);
+
+    fn set_allocation_options(&mut self, node: NodeId, options: AllocationOptions);
+
+    /// Set a human-readable name for a node, used when printing or dumping the graph.
+    fn set_label(&mut self, node: NodeId, label: String);
+
+    /// Add an output to a node, in addition to its main output.
+    fn add_output(&mut self, node: NodeId, target_kind: TargetKind, size: Size) -> NodeOutput;
+
+    /// Make a node read a specific output of another node.
+    ///
+    /// Depending on a node with `add_node` or `add_dependency` reads its main output.
+    fn add_output_dependency(&mut self, node: NodeId, output: NodeOutput);
+
+    /// Attach some data to a node, replacing the previous data if any.
+    fn set_user_data(&mut self, node: NodeId, data: U);
 }
 
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 #[derive(Clone)]
-pub struct Node {
-    pub task_id: TaskId,
+pub struct Node<T = TaskId> {
+    pub task_id: T,
     pub size: Size,
     pub alloc_kind: AllocKind,
     pub dependencies: SmallVec<[NodeId; 2]>,
     pub target_kind: TargetKind,
+    #[cfg_attr(feature = "serialization", serde(default))]
+    pub alloc_options: AllocationOptions,
+    #[cfg_attr(feature = "serialization", serde(default))]
+    pub label: Option<String>,
+    /// Outputs of the node after the main one.
+    #[cfg_attr(feature = "serialization", serde(default))]
+    pub extra_outputs: Vec<OutputDescriptor>,
+    /// Outputs read by this node, other than the main outputs of its dependencies.
+    #[cfg_attr(feature = "serialization", serde(default))]
+    pub output_dependencies: Vec<NodeOutput>,
+}
+
+impl<T> Node<T> {
+    pub(crate) fn new(
+        task_id: T,
+        target_kind: TargetKind,
+        size: Size,
+        alloc_kind: AllocKind,
+        deps: &[NodeId],
+    ) -> Self {
+        Node {
+            task_id,
+            size,
+            alloc_kind,
+            dependencies: SmallVec::from_slice(deps),
+            target_kind,
+            alloc_options: AllocationOptions::default(),
+            label: None,
+            extra_outputs: Vec::new(),
+            output_dependencies: Vec::new(),
+        }
+    }
+
+    pub fn num_outputs(&self) -> usize {
+        1 + self.extra_outputs.len()
+    }
+
+    pub fn is_external(&self) -> bool {
+        matches!(self.alloc_kind, AllocKind::External(..))
+    }
+
+    fn external_rectangle(&self) -> Option<Rectangle> {
+        match self.alloc_kind {
+            AllocKind::External(_, origin) => Some(Rectangle {
+                min: origin,
+                max: origin + self.size.to_vector(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn output_size(&self, index: u32) -> Size {
+        match index {
+            0 => self.size,
+            _ => self.extra_outputs[index as usize - 1].size,
+        }
+    }
+
+    pub fn output_target_kind(&self, index: u32) -> TargetKind {
+        match index {
+            0 => self.target_kind,
+            _ => self.extra_outputs[index as usize - 1].target_kind,
+        }
+    }
+
+    /// The outputs read by this node.
+    pub fn inputs(&self) -> impl Iterator<Item = NodeOutput> + '_ {
+        self.dependencies.iter()
+            .map(|&dep| NodeOutput::main(dep))
+            .chain(self.output_dependencies.iter().cloned())
+    }
 }
 
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
@@ -96,6 +235,72 @@ pub struct Node {
 pub enum AllocKind {
     Fixed(TextureId, Point),
     Dynamic,
+    /// A read-only input produced outside of the graph, for example a video frame or
+    /// the output of a previous frame, at a given position in a texture.
+    ///
+    /// External inputs are not rendered nor allocated, and are not part of any pass.
+    /// They can't have dependencies nor additional outputs.
+    External(TextureId, Point),
+    /// Render into the rectangle of a dependency instead of a new allocation, for
+    /// example to draw on top of an existing picture.
+    ///
+    /// The node must depend on the given node, have the same size and target kind and
+    /// no additional outputs. The dependency can't be a root nor an external input, and
+    /// it can't be read by other nodes from the pass of the in-place node onward since
+    /// its content is overwritten. Both nodes render into the same texture and the
+    /// allocation of the dependency lives as long as the in-place node is needed.
+    InPlace(NodeId),
+}
+
+/// Controls how the rectangle of a node is placed in its render target.
+///
+/// The size of a node is the size of its usable inner rectangle. Padding is added
+/// on each side of it and is part of the node's allocation (for example the area a
+/// blur samples from), while the border is kept unused around the padded allocation
+/// so that filtering doesn't bleed into neighboring allocations.
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct AllocationOptions {
+    /// Number of pixels added on each side of the inner rectangle.
+    pub padding: i32,
+    /// Number of pixels kept unused on each side of the padded rectangle.
+    ///
+    /// Only applies to dynamic allocations: fixed nodes are placed at their origin and
+    /// it is up to the user to keep space around them.
+    pub border: i32,
+    /// If greater than one, the origin of the padded rectangle is a multiple of this value.
+    ///
+    /// Only applies to dynamic allocations.
+    pub alignment: i32,
+}
+
+impl AllocationOptions {
+    /// Size of the rectangle including the padding.
+    pub fn padded_size(&self, size: Size) -> Size {
+        size + size2(self.padding, self.padding) * 2
+    }
+
+    /// Size to request from the texture allocator so that the padded rectangle, its
+    /// border and the alignment slack fit in the allocation.
+    pub fn allocation_size(&self, size: Size) -> Size {
+        let slack = self.alignment.max(1) - 1;
+        self.padded_size(size) + size2(self.border, self.border) * 2 + size2(slack, slack)
+    }
+
+    /// Place the padded rectangle within an allocation of `allocation_size(size)`.
+    pub fn padded_rectangle(&self, allocation: &Rectangle, size: Size) -> Rectangle {
+        let alignment = self.alignment.max(1);
+        let align = |v: i32| (v + alignment - 1) / alignment * alignment;
+        let min = point2(
+            align(allocation.min.x + self.border),
+            align(allocation.min.y + self.border),
+        );
+
+        Rectangle {
+            min,
+            max: min + self.padded_size(size).to_vector(),
+        }
+    }
 }
 
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
@@ -105,37 +310,64 @@ pub enum TaskId {
     Render(u16, u32),
 }
 
+/// Creates the tasks that copy the content of a node into another target.
+///
+/// Copy tasks are inserted in the graph by the ping-pong target assignment when a node
+/// would otherwise read from and write to the same target.
+pub trait CopyTaskFactory<T> {
+    /// Create the task copying `source`, which is an output of the node of `source_task`.
+    fn create_copy_task(&mut self, source: NodeOutput, source_task: &T) -> T;
+}
+
+impl<T, F: FnMut(NodeOutput, &T) -> T> CopyTaskFactory<T> for F {
+    fn create_copy_task(&mut self, source: NodeOutput, source_task: &T) -> T {
+        self(source, source_task)
+    }
+}
+
+/// A graph of render tasks.
+///
+/// `T` describes the task of each node. Each node can optionally carry some user data of
+/// type `U`, for example what the backend needs to draw it. Graphs of `TaskId` without
+/// user data can be created with `Graph::new`, others with `Graph::default` or
+/// `Graph::with_capacity`.
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 #[derive(Clone)]
-pub struct Graph {
-    pub(crate) nodes: Vec<Node>,
+pub struct Graph<T = TaskId, U = ()> {
+    pub(crate) nodes: Vec<Node<T>>,
     pub(crate) roots: Vec<NodeId>,
+    #[cfg_attr(feature = "serialization", serde(default))]
+    pub(crate) side_effects: Vec<NodeId>,
+    /// Indexed by node. Can be shorter than `nodes`.
+    #[cfg_attr(feature = "serialization", serde(default = "Vec::new"))]
+    pub(crate) user_data: Vec<Option<U>>,
 }
 
 impl Graph {
+    pub fn new() -> Self {
+        Graph::with_capacity(0, 0)
+    }
+}
+
+impl<T, U> Default for Graph<T, U> {
+    fn default() -> Self {
+        Graph::with_capacity(0, 0)
+    }
+}
+
+impl<T, U> Graph<T, U> {
     pub fn with_capacity(nodes: usize, roots: usize) -> Self {
         Graph {
             nodes: Vec::with_capacity(nodes),
             roots: Vec::with_capacity(roots),
+            side_effects: Vec::new(),
+            user_data: Vec::new(),
         }
     }
 
-    pub fn new() -> Self {
-        Graph {
-            nodes: Vec::new(),
-            roots: Vec::new(),
-        }
-    }
-
-    pub fn add_node(&mut self, task_id: TaskId, target_kind: TargetKind, size: Size, alloc_kind: AllocKind, deps: &[NodeId]) -> NodeId {
+    pub fn add_node(&mut self, task_id: T, target_kind: TargetKind, size: Size, alloc_kind: AllocKind, deps: &[NodeId]) -> NodeId {
         let id = node_id(self.nodes.len());
-        self.nodes.push(Node {
-            task_id,
-            size,
-            alloc_kind,
-            dependencies: SmallVec::from_slice(deps),
-            target_kind,
-        });
+        self.nodes.push(Node::new(task_id, target_kind, size, alloc_kind, deps));
 
         id
     }
@@ -148,10 +380,78 @@ impl Graph {
         self.roots.push(id);
     }
 
+    /// See `GraphBuilder::add_side_effect`.
+    pub fn add_side_effect(&mut self, id: NodeId) {
+        self.side_effects.push(id);
+    }
+
+    pub fn set_allocation_options(&mut self, node: NodeId, options: AllocationOptions) {
+        self.nodes[node.index()].alloc_options = options;
+    }
+
+    pub fn set_label(&mut self, node: NodeId, label: String) {
+        self.nodes[node.index()].label = Some(label);
+    }
+
+    pub fn label(&self, node: NodeId) -> Option<&str> {
+        self.nodes[node.index()].label.as_deref()
+    }
+
+    pub fn add_output(&mut self, node: NodeId, target_kind: TargetKind, size: Size) -> NodeOutput {
+        let outputs = &mut self.nodes[node.index()].extra_outputs;
+        outputs.push(OutputDescriptor { target_kind, size });
+
+        NodeOutput::new(node, outputs.len() as u32)
+    }
+
+    pub fn add_output_dependency(&mut self, node: NodeId, output: NodeOutput) {
+        if output.index == 0 {
+            self.add_dependency(node, output.node);
+        } else {
+            assert!((output.index as usize) < self.nodes[output.node.index()].num_outputs());
+            self.nodes[node.index()].output_dependencies.push(output);
+        }
+    }
+
+    /// The outputs read by a node: the main output of each of its dependencies and the
+    /// outputs added with `add_output_dependency`.
+    pub fn node_inputs(&self, node: NodeId) -> impl Iterator<Item = NodeOutput> + '_ {
+        self.nodes[node.index()].inputs()
+    }
+
+    pub fn set_user_data(&mut self, node: NodeId, data: U) {
+        let idx = node.index();
+        assert!(idx < self.nodes.len());
+        if idx >= self.user_data.len() {
+            self.user_data.resize_with(idx + 1, || None);
+        }
+        self.user_data[idx] = Some(data);
+    }
+
+    pub fn user_data(&self, node: NodeId) -> Option<&U> {
+        self.user_data.get(node.index())?.as_ref()
+    }
+
+    pub fn user_data_mut(&mut self, node: NodeId) -> Option<&mut U> {
+        self.user_data.get_mut(node.index())?.as_mut()
+    }
+
+    /// The label of the node if any, otherwise its id.
+    pub fn node_name(&self, node: NodeId) -> String {
+        match self.label(node) {
+            Some(label) => label.to_string(),
+            None => format!("{:?}", node),
+        }
+    }
+
     pub fn roots(&self) -> &[NodeId] {
         &self.roots
     }
 
+    pub fn side_effects(&self) -> &[NodeId] {
+        &self.side_effects
+    }
+
     pub fn node_ids(&self) -> NodeIdRange {
         NodeIdRange {
             start: 0,
@@ -167,10 +467,71 @@ impl Graph {
         &self.nodes[node.index()].dependencies
     }
 
-    pub fn build(mut self, options: &BuilderOptions, allocator: &mut dyn TextureAllocator) -> BuiltGraph {
+    /// The node which allocation `node` renders into: the node itself unless it
+    /// renders in place, in which case the chain of in-place nodes is followed.
+    pub fn in_place_owner(&self, mut node: NodeId) -> NodeId {
+        while let AllocKind::InPlace(dep) = self.nodes[node.index()].alloc_kind {
+            node = dep;
+        }
+
+        node
+    }
+
+    /// Build the graph, using `copy_tasks` to create the copy tasks that may be
+    /// inserted in the graph.
+    pub fn build_with_copy_tasks(
+        mut self,
+        options: &BuilderOptions,
+        allocator: &mut dyn TextureAllocator,
+        copy_tasks: &mut dyn CopyTaskFactory<T>,
+    ) -> Result<BuiltGraph<T, U>, BuildError> where T: Clone {
+
+        // In-place nodes must depend on another existing node to render into. This is
+        // checked before anything follows the chains of in-place nodes.
+        for id in self.node_ids() {
+            let node = &self.nodes[id.index()];
+            if let AllocKind::InPlace(dep) = node.alloc_kind {
+                if dep == id || dep.index() >= self.nodes.len() || !node.dependencies.contains(&dep) {
+                    return Err(BuildError::InvalidInPlaceNode(id));
+                }
+            }
+        }
+
+        // Fixed targets and external inputs can't use the namespace reserved for dynamic
+        // textures.
+        for id in self.node_ids() {
+            let node = &self.nodes[id.index()];
+            let options = &node.alloc_options;
+            if options.padding < 0 || options.border < 0 || options.alignment < 0 {
+                return Err(BuildError::InvalidAllocationOptions(id));
+            }
+            match node.alloc_kind {
+                AllocKind::Fixed(texture, _) | AllocKind::External(texture, _) if texture.is_dynamic() => {
+                    return Err(BuildError::ReservedTextureId(id, texture));
+                }
+                AllocKind::External(..) if !node.dependencies.is_empty()
+                    || !node.output_dependencies.is_empty()
+                    || !node.extra_outputs.is_empty() => {
+                    return Err(BuildError::InvalidExternalInput(id));
+                }
+                AllocKind::InPlace(dep) => {
+                    let dep_node = &self.nodes[dep.index()];
+                    if !node.extra_outputs.is_empty()
+                        || dep_node.size != node.size
+                        || dep_node.target_kind != node.target_kind
+                        || dep_node.alloc_options != node.alloc_options
+                        || self.roots.contains(&dep)
+                        || self.nodes[self.in_place_owner(dep).index()].is_external() {
+                        return Err(BuildError::InvalidInPlaceNode(id));
+                    }
+                }
+                _ => {}
+            }
+        }
 
         let mut passes = Vec::new();
         let mut node_passes = vec![i32::MAX; self.nodes.len()];
+        let mut inputs = Vec::new();
 
 
         // Step 1 - Assign nodes to passes.
@@ -182,54 +543,104 @@ impl Graph {
             &self,
             &mut passes,
             &mut node_passes,
+            &mut inputs,
         );
 
+        check_external_inputs(&self, &passes, &node_passes, &inputs)?;
+        check_in_place_readers(&self, &node_passes)?;
+
         // Step 2 - assign render targets to passes.
         //
         // A render target can be used by several passes as long as no pass
         // both read and write the same render target.
 
+        // Planned allocations don't go through the texture allocator, so it isn't told
+        // about the dynamic textures either.
+        let planned = options.allocations == AllocationStrategy::Planned;
+        let target_allocator = if planned { None } else { Some(&mut *allocator) };
+
+        let num_nodes = self.nodes.len();
         match options.targets {
             TargetOptions::Direct => assign_targets_direct(
                 &mut self,
                 &mut passes,
                 &mut node_passes,
-                allocator,
+                target_allocator,
             ),
             TargetOptions::PingPong => assign_targets_ping_pong(
                 &mut self,
                 &mut passes,
                 &mut node_passes,
-                allocator,
-            ),
+                target_allocator,
+                copy_tasks,
+            )?,
         }
 
+        place_in_place_nodes(&self, &mut passes, &node_passes)?;
+
+        let copy_nodes = (num_nodes..self.nodes.len()).map(node_id).collect();
+
         // Step 3 - Allocate portions of the render targets for each node.
         //
         // Several nodes can alias parts of a render target as long no node
         // overwrite the result of a node that will be needed later.
 
-        let mut allocated_rectangles = vec![Rectangle::zero(); self.nodes.len()];
+        let mut allocated_rectangles: Vec<NodeRectangles> = self.nodes.iter()
+            .map(|node| smallvec![Rectangle::zero(); node.num_outputs()])
+            .collect();
+        let mut allocations = Vec::new();
+
+        match options.allocations {
+            AllocationStrategy::PassByPass => allocate_target_rects(
+                &self,
+                &passes,
+                options.deallocate_before_allocate,
+                &mut allocated_rectangles,
+                &mut allocations,
+                allocator,
+            ),
+            AllocationStrategy::Planned => plan_target_rects(
+                &self,
+                &passes,
+                &mut allocated_rectangles,
+                &mut allocations,
+            ),
+        }
 
-        allocate_target_rects(
-            &mut self,
-            &mut passes,
-            &mut allocated_rectangles,
-            allocator,
-        );
+        for &input in &inputs {
+            allocated_rectangles[input.index()][0] = self.nodes[input.index()].external_rectangle().unwrap();
+        }
+
+        for id in self.node_ids() {
+            if let AllocKind::InPlace(..) = self.nodes[id.index()].alloc_kind {
+                allocated_rectangles[id.index()][0] = allocated_rectangles[self.in_place_owner(id).index()][0];
+            }
+        }
 
-        BuiltGraph {
+        let texture_sizes = dynamic_texture_sizes(&passes, &allocated_rectangles, allocator, planned);
+
+        Ok(BuiltGraph {
             graph: self,
             allocated_rectangles,
             passes,
-        }
+            inputs,
+            texture_sizes,
+            copy_nodes,
+            allocations,
+        })
     }
 }
 
-impl GraphBuilder for Graph {
+impl<U> Graph<TaskId, U> {
+    pub fn build(self, options: &BuilderOptions, allocator: &mut dyn TextureAllocator) -> Result<BuiltGraph<TaskId, U>, BuildError> {
+        self.build_with_copy_tasks(options, allocator, &mut |_, _: &TaskId| TaskId::Copy)
+    }
+}
+
+impl<T, U> GraphBuilder<T, U> for Graph<T, U> {
     fn add_node(
         &mut self,
-        task_id: TaskId,
+        task_id: T,
         target_kind: TargetKind,
         size: Size,
         alloc_kind: AllocKind,
@@ -245,58 +656,173 @@ impl GraphBuilder for Graph {
     fn add_root(&mut self, node: NodeId) {
         Graph::add_root(self, node);
     }
+
+    fn add_side_effect(&mut self, node: NodeId) {
+        Graph::add_side_effect(self, node);
+    }
+
+    fn set_allocation_options(&mut self, node: NodeId, options: AllocationOptions) {
+        Graph::set_allocation_options(self, node, options);
+    }
+
+    fn set_label(&mut self, node: NodeId, label: String) {
+        Graph::set_label(self, node, label);
+    }
+
+    fn add_output(&mut self, node: NodeId, target_kind: TargetKind, size: Size) -> NodeOutput {
+        Graph::add_output(self, node, target_kind, size)
+    }
+
+    fn add_output_dependency(&mut self, node: NodeId, output: NodeOutput) {
+        Graph::add_output_dependency(self, node, output);
+    }
+
+    fn set_user_data(&mut self, node: NodeId, data: U) {
+        Graph::set_user_data(self, node, data);
+    }
 }
 
-impl std::ops::Index<NodeId> for Graph {
-    type Output = Node;
-    fn index(&self, id: NodeId) -> &Node {
+impl<T, U> std::ops::Index<NodeId> for Graph<T, U> {
+    type Output = Node<T>;
+    fn index(&self, id: NodeId) -> &Node<T> {
         &self.nodes[id.index()]
     }
 }
 
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
-pub struct BuiltGraph {
-    graph: Graph,
-    allocated_rectangles: Vec<Rectangle>,
-    passes: Vec<Pass>,
+pub struct BuiltGraph<T = TaskId, U = ()> {
+    graph: Graph<T, U>,
+    /// Indexed by node, then by output.
+    allocated_rectangles: Vec<NodeRectangles>,
+    passes: Vec<Pass<T>>,
+    #[cfg_attr(feature = "serialization", serde(default))]
+    inputs: Vec<NodeId>,
+    /// Indexed by dynamic texture index.
+    #[cfg_attr(feature = "serialization", serde(default))]
+    texture_sizes: Vec<Size>,
+    #[cfg_attr(feature = "serialization", serde(default))]
+    copy_nodes: Vec<NodeId>,
+    #[cfg_attr(feature = "serialization", serde(default))]
+    allocations: Vec<AllocationRecord>,
+}
+
+/// The passes during which a dynamically allocated rectangle is reserved for an output.
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AllocationRecord {
+    pub output: NodeOutput,
+    pub texture: TextureId,
+    /// The pass the output is rendered in.
+    pub allocated: usize,
+    /// The first pass in which the rectangle can be reused, `None` if it is kept until
+    /// the end of the graph.
+    pub deallocated: Option<usize>,
 }
 
-impl BuiltGraph {
+/// The rectangles allocated for each output of a node.
+pub(crate) type NodeRectangles = SmallVec<[Rectangle; 1]>;
+
+impl<T, U> BuiltGraph<T, U> {
+    /// The rectangle allocated for the main output of a node, including its padding.
     pub fn allocated_rectangle(&self, node: NodeId) -> &Rectangle {
-        &self.allocated_rectangles[node.index()]
+        &self.allocated_rectangles[node.index()][0]
     }
 
-    pub fn passes(&self) -> &[Pass] {
+    /// The usable part of the node's main allocation, without the padding.
+    pub fn inner_rectangle(&self, node: NodeId) -> Rectangle {
+        self.inner_output_rectangle(NodeOutput::main(node))
+    }
+
+    /// The rectangle allocated for an output, including its padding.
+    pub fn output_rectangle(&self, output: NodeOutput) -> &Rectangle {
+        &self.allocated_rectangles[output.node.index()][output.index as usize]
+    }
+
+    /// The usable part of an output's allocation, without the padding.
+    pub fn inner_output_rectangle(&self, output: NodeOutput) -> Rectangle {
+        let padding = self.graph.nodes[output.node.index()].alloc_options.padding;
+        self.output_rectangle(output).inflate(-padding, -padding)
+    }
+
+    pub fn passes(&self) -> &[Pass<T>] {
         &self.passes
     }
+
+    /// The external inputs read by the graph.
+    pub fn inputs(&self) -> &[NodeId] {
+        &self.inputs
+    }
+
+    /// The dynamic allocations of the graph and the passes they live in.
+    pub fn allocations(&self) -> &[AllocationRecord] {
+        &self.allocations
+    }
+
+    /// The copy nodes added by the builder so that no node reads and writes the same
+    /// target, see `TargetOptions::PingPong`.
+    pub fn copy_nodes(&self) -> &[NodeId] {
+        &self.copy_nodes
+    }
+
+    /// The size of a dynamic texture once all of its rectangles are allocated.
+    ///
+    /// This is the size reported by the texture allocator, or the extents of the
+    /// allocated rectangles if the allocator doesn't know it or with planned allocations.
+    /// Returns `None` for fixed textures, which are provided by the user.
+    pub fn texture_size(&self, texture: TextureId) -> Option<Size> {
+        if !texture.is_dynamic() {
+            return None;
+        }
+
+        self.texture_sizes.get(texture.index()).cloned()
+    }
+
+    /// Memory used by the dynamic textures, useful to compare allocation strategies.
+    pub fn memory_stats(&self) -> MemoryStats {
+        memory_stats(&self.graph, &self.passes, &self.allocated_rectangles)
+    }
 }
 
-impl std::ops::Deref for BuiltGraph {
-    type Target = Graph;
-    fn deref(&self) -> &Graph {
+impl<T, U> std::ops::Deref for BuiltGraph<T, U> {
+    type Target = Graph<T, U>;
+    fn deref(&self) -> &Graph<T, U> {
         &self.graph
     }
 }
 
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
-pub struct Pass {
-    pub dynamic_targets: [PassTarget; NUM_TARGET_KINDS],
-    pub fixed_targets: Vec<PassTarget>,
+pub struct Pass<T = TaskId> {
+    pub dynamic_targets: [PassTarget<T>; NUM_TARGET_KINDS],
+    pub fixed_targets: Vec<PassTarget<T>>,
 }
 
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
-pub struct Task {
+pub struct Task<T = TaskId> {
     pub node_id: NodeId,
-    pub task_id: TaskId,
+    /// The output of the node that is written into the target.
+    pub output: u32,
+    pub task_id: T,
 }
 
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
-pub struct PassTarget {
-    pub(crate) tasks: Vec<Task>,
+pub struct PassTarget<T = TaskId> {
+    pub(crate) tasks: Vec<Task<T>>,
     pub(crate) destination: Option<TextureId>,
 }
 
+impl<T> PassTarget<T> {
+    /// The tasks rendered into the target, in order.
+    pub fn tasks(&self) -> &[Task<T>] {
+        &self.tasks
+    }
+
+    /// The texture the target renders into, `None` if the target has no tasks.
+    pub fn destination(&self) -> Option<TextureId> {
+        self.destination
+    }
+}
+
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum TargetDestination {
@@ -311,67 +837,228 @@ pub enum TargetOptions {
     PingPong,
 }
 
+/// How the rectangles of dynamic nodes are allocated in their render targets.
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum AllocationStrategy {
+    /// Allocate and deallocate rectangles pass by pass using the texture allocator.
+    PassByPass,
+    /// Compute the lifetime of every node up front and pack the rectangles of each
+    /// texture over time, without going through the texture allocator.
+    Planned,
+}
+
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct BuilderOptions {
     pub targets: TargetOptions,
+    #[cfg_attr(feature = "serialization", serde(default = "default_allocation_strategy"))]
+    pub allocations: AllocationStrategy,
+    /// Free the allocations of the inputs that are read for the last time by a pass at
+    /// the beginning of that pass rather than at the end, when they don't live in any
+    /// of the textures the pass renders into.
+    ///
+    /// Only affects the pass-by-pass allocation strategy.
+    #[cfg_attr(feature = "serialization", serde(default))]
+    pub deallocate_before_allocate: bool,
+}
+
+impl Default for BuilderOptions {
+    fn default() -> Self {
+        BuilderOptions {
+            targets: TargetOptions::Direct,
+            allocations: AllocationStrategy::PassByPass,
+            deallocate_before_allocate: false,
+        }
+    }
+}
+
+#[cfg(feature = "serialization")]
+fn default_allocation_strategy() -> AllocationStrategy {
+    AllocationStrategy::PassByPass
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BuildError {
+    /// A node has a fixed allocation in a texture which id is in the namespace
+    /// reserved for dynamic textures.
+    ReservedTextureId(NodeId, TextureId),
+    /// A node has a negative padding, border or alignment.
+    InvalidAllocationOptions(NodeId),
+    /// An external input has dependencies or additional outputs.
+    InvalidExternalInput(NodeId),
+    /// A node with a fixed allocation overwrites an external input before it is read
+    /// for the last time, or writes into the texture of an external input in a pass
+    /// that reads the input.
+    ExternalInputOverwritten {
+        node: NodeId,
+        input: NodeId,
+    },
+    /// An in-place node doesn't depend on the node it renders into, or they don't have
+    /// the same size, target kind and allocation options, or the latter is the in-place
+    /// node itself, a root or an external input.
+    InvalidInPlaceNode(NodeId),
+    /// A node reads from the rectangle or the texture an in-place node renders into
+    /// while it is being overwritten.
+    InPlaceConflict {
+        node: NodeId,
+        reader: NodeId,
+    },
+    /// Two in-place nodes render into the same node.
+    InPlaceWriters {
+        node: NodeId,
+        other: NodeId,
+    },
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            BuildError::ReservedTextureId(node, texture) => write!(
+                f, "{:?} uses {:?} which is reserved for dynamic textures", node, texture
+            ),
+            BuildError::InvalidAllocationOptions(node) => write!(
+                f, "{:?} has a negative padding, border or alignment", node
+            ),
+            BuildError::InvalidExternalInput(node) => write!(
+                f, "external input {:?} can't have dependencies or additional outputs", node
+            ),
+            BuildError::ExternalInputOverwritten { node, input } => write!(
+                f, "{:?} overwrites external input {:?} before it is read", node, input
+            ),
+            BuildError::InvalidInPlaceNode(node) => write!(
+                f, "in-place node {:?} can't render into its dependency", node
+            ),
+            BuildError::InPlaceConflict { node, reader } => write!(
+                f, "{:?} reads from the target in-place node {:?} renders into", reader, node
+            ),
+            BuildError::InPlaceWriters { node, other } => write!(
+                f, "in-place nodes {:?} and {:?} render into the same node", other, node
+            ),
+        }
+    }
 }
 
+impl std::error::Error for BuildError {}
+
 /// Create render passes and assign the nodes to them.
 ///
 /// This method tries to emulate WebRender's current behavior.
 /// Nodes are executed as late as possible.
-fn create_passes(
-    graph: &Graph,
-    passes: &mut Vec<Pass>,
+fn create_passes<T: Clone, U>(
+    graph: &Graph<T, U>,
+    passes: &mut Vec<Pass<T>>,
     node_passes: &mut [i32],
+    inputs: &mut Vec<NodeId>,
 ) {
-    // Recursively traverse the graph from the roots and assign a "depth" to each node.
-    // The depth of a node is its maximum distance to a root, used to decide which pass
-    // each node gets assigned to by simply computing `node_pass = max_depth - node_depth`.
-    // This scheme ensures that nodes are executed in passes prior to nodes that depend
-    // on them.
-
-    fn assign_depths(
-        graph: &Graph,
+    // Recursively traverse the graph from the roots and side effects and assign a "depth"
+    // to each node. The depth of a node is its maximum distance to a root or a side
+    // effect, used to decide which pass each node gets assigned to by simply computing
+    // `node_pass = max_depth - node_depth`. This scheme ensures that nodes are executed
+    // in passes prior to nodes that depend on them.
+
+    /// Where the depths are stored during the traversal.
+    trait Depths {
+        fn raise(&mut self, node_id: NodeId, depth: i32);
+    }
+
+    impl Depths for [i32] {
+        fn raise(&mut self, node_id: NodeId, depth: i32) {
+            self[node_id.index()] = std::cmp::max(self[node_id.index()], depth);
+        }
+    }
+
+    impl Depths for HashMap<NodeId, i32> {
+        fn raise(&mut self, node_id: NodeId, depth: i32) {
+            let entry = self.entry(node_id).or_insert(depth);
+            *entry = std::cmp::max(*entry, depth);
+        }
+    }
+
+    fn assign_depths<D: Depths + ?Sized>(
+        dependencies: &[SmallVec<[NodeId; 2]>],
         node_id: NodeId,
         rev_pass_index: i32,
-        node_rev_passes: &mut [i32],
-        max_depth: &mut i32,
+        node_rev_passes: &mut D,
     ) {
-        *max_depth = std::cmp::max(*max_depth, rev_pass_index);
-
-        node_rev_passes[node_id.index()] = std::cmp::max(
-            node_rev_passes[node_id.index()],
-            rev_pass_index,
-        );
+        node_rev_passes.raise(node_id, rev_pass_index);
 
-        for &dep in &graph.nodes[node_id.index()].dependencies {
+        for &dep in &dependencies[node_id.index()] {
             assign_depths(
-                graph,
+                dependencies,
                 dep,
                 rev_pass_index + 1,
                 node_rev_passes,
-                max_depth,
             );
         }
     }
 
+    let dependencies: Vec<SmallVec<[NodeId; 2]>> = graph.nodes.iter()
+        .map(|node| node.inputs().map(|input| input.node).collect())
+        .collect();
+
     // Initialize the array with negative values. Once the recusive passes are done, any negative
     // value left corresponds to nodes that haven't been traversed, which means they are not
     // contributing to the output of the graph. They won't be assigned to any pass.
-    let mut node_rev_passes = vec![-1; graph.nodes.len()];
-    let mut max_depth = 0;
-
-    for &root in &graph.roots {
-        assign_depths(
-            &graph,
-            root,
-            0,
-            &mut node_rev_passes,
-            &mut max_depth,
+    #[cfg(not(feature = "rayon"))]
+    let node_rev_passes = {
+        let mut node_rev_passes = vec![-1; graph.nodes.len()];
+
+        for &root in graph.roots.iter().chain(graph.side_effects.iter()) {
+            assign_depths(
+                &dependencies,
+                root,
+                0,
+                &mut node_rev_passes[..],
+            );
+        }
+
+        node_rev_passes
+    };
+
+    // Each thread traverses the graph from a subset of the roots, and the per-thread depths
+    // are merged by taking the maximum, which gives the same result as the serial traversal.
+    // The per-thread depths only contain the nodes reached from these roots.
+    #[cfg(feature = "rayon")]
+    let node_rev_passes = {
+        use rayon::prelude::*;
+
+        let depths = graph.roots.par_iter().chain(graph.side_effects.par_iter()).fold(
+            HashMap::new,
+            |mut node_rev_passes, &root| {
+                assign_depths(
+                    &dependencies,
+                    root,
+                    0,
+                    &mut node_rev_passes,
+                );
+                node_rev_passes
+            }
+        ).reduce(
+            HashMap::new,
+            |a, b| {
+                let (mut a, b) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+                for (node_id, depth) in b {
+                    a.raise(node_id, depth);
+                }
+                a
+            }
         );
-    }
+
+        let mut node_rev_passes = vec![-1; graph.nodes.len()];
+        for (node_id, depth) in depths {
+            node_rev_passes[node_id.index()] = depth;
+        }
+
+        node_rev_passes
+    };
+
+    // External inputs don't need a pass.
+    let max_depth = graph.node_ids()
+        .filter(|id| !graph.nodes[id.index()].is_external())
+        .map(|id| node_rev_passes[id.index()])
+        .max()
+        .unwrap_or(0);
 
     for _ in 0..(max_depth + 1) {
         passes.push(Pass {
@@ -396,6 +1083,11 @@ fn create_passes(
             continue;
         }
 
+        if graph.nodes[node_idx].is_external() {
+            inputs.push(id);
+            continue;
+        }
+
         let target_kind = graph.nodes[node_idx].target_kind;
         let pass_index = (max_depth - node_rev_passes[node_idx]) as usize;
         let node = &graph.nodes[node_idx];
@@ -403,57 +1095,263 @@ fn create_passes(
             AllocKind::Dynamic => {
                 passes[pass_index].dynamic_targets[target_kind as usize].tasks.push(Task {
                     node_id: id,
-                    task_id: node.task_id,
+                    output: 0,
+                    task_id: node.task_id.clone(),
                 });
             }
+            AllocKind::External(..) => unreachable!(),
+            // Placed once the texture of the node they render into is known.
+            AllocKind::InPlace(..) => {}
             AllocKind::Fixed(texture_id, ..) => {
                 let task = Task {
                     node_id: id,
-                    task_id: node.task_id,
+                    output: 0,
+                    task_id: node.task_id.clone(),
                 };
-                let mut new_target = true;
-                for target in &mut passes[pass_index].fixed_targets {
-                    if target.destination == Some(texture_id) {
-                        new_target = false;
-                        target.tasks.push(task);
-                        break;
-                    }
-                }
-                if new_target {
-                    passes[pass_index].fixed_targets.push(PassTarget {
+                let fixed_targets = &mut passes[pass_index].fixed_targets;
+                match fixed_targets.iter_mut().find(|target| target.destination == Some(texture_id)) {
+                    Some(target) => target.tasks.push(task),
+                    None => fixed_targets.push(PassTarget {
                         tasks: vec![task],
                         destination: Some(texture_id),
-                    });
+                    }),
                 }
             }
         }
+        for (idx, output) in node.extra_outputs.iter().enumerate() {
+            passes[pass_index].dynamic_targets[output.target_kind as usize].tasks.push(Task {
+                node_id: id,
+                output: idx as u32 + 1,
+                task_id: node.task_id.clone(),
+            });
+        }
         node_passes[node_idx] = pass_index as i32;
     }
 }
 
+/// Check that the external inputs are not overwritten by nodes with fixed allocations
+/// in the same texture before they are read for the last time, and that their texture
+/// isn't written at all in the passes that read them.
+///
+/// In-place nodes rendering into nodes with fixed allocations write into the texture of
+/// the latter.
+fn check_external_inputs<T, U>(
+    graph: &Graph<T, U>,
+    passes: &[Pass<T>],
+    node_passes: &[i32],
+    inputs: &[NodeId],
+) -> Result<(), BuildError> {
+    if inputs.is_empty() {
+        return Ok(());
+    }
+
+    // The passes reading each input, in order.
+    let mut reads: HashMap<NodeId, Vec<usize>> = HashMap::new();
+    for (pass_index, pass) in passes.iter().enumerate() {
+        for target in pass.dynamic_targets.iter().chain(pass.fixed_targets.iter()) {
+            for task in &target.tasks {
+                for input in graph.node_inputs(task.node_id) {
+                    if graph.nodes[input.node.index()].is_external() {
+                        reads.entry(input.node).or_default().push(pass_index);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut writes = Vec::new();
+    for id in graph.node_ids() {
+        let pass_index = node_passes[id.index()];
+        if pass_index == i32::MAX {
+            continue;
+        }
+        let owner = &graph.nodes[graph.in_place_owner(id).index()];
+        if let AllocKind::Fixed(texture, origin) = owner.alloc_kind {
+            let rect = Rectangle {
+                min: origin,
+                max: origin + owner.alloc_options.padded_size(owner.size).to_vector(),
+            };
+            writes.push((id, pass_index as usize, texture, rect));
+        }
+    }
+
+    for &input in inputs {
+        let input_node = &graph.nodes[input.index()];
+        let texture = match input_node.alloc_kind {
+            AllocKind::External(texture, _) => texture,
+            _ => unreachable!(),
+        };
+        let input_rect = input_node.external_rectangle().unwrap();
+        let read_passes = match reads.get(&input) {
+            Some(read_passes) => read_passes,
+            None => continue,
+        };
+        let last_read = *read_passes.last().unwrap();
+
+        for &(node, pass_index, written, rect) in &writes {
+            if written != texture {
+                continue;
+            }
+            if read_passes.contains(&pass_index) || (pass_index <= last_read && rect.intersects(&input_rect)) {
+                return Err(BuildError::ExternalInputOverwritten { node, input });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that the nodes that in-place nodes render into are not read from the pass of
+/// the in-place node onward.
+fn check_in_place_readers<T, U>(
+    graph: &Graph<T, U>,
+    node_passes: &[i32],
+) -> Result<(), BuildError> {
+    let mut writers = HashMap::new();
+    for id in graph.node_ids() {
+        if node_passes[id.index()] == i32::MAX {
+            continue;
+        }
+        if let AllocKind::InPlace(dep) = graph.nodes[id.index()].alloc_kind {
+            if let Some(other) = writers.insert(dep, id) {
+                return Err(BuildError::InPlaceWriters { node: id, other });
+            }
+        }
+    }
+
+    if writers.is_empty() {
+        return Ok(());
+    }
+
+    for id in graph.node_ids() {
+        if node_passes[id.index()] == i32::MAX {
+            continue;
+        }
+        for input in graph.node_inputs(id) {
+            if input.index != 0 {
+                continue;
+            }
+            if let Some(&writer) = writers.get(&input.node) {
+                if writer != id && node_passes[id.index()] >= node_passes[writer.index()] {
+                    return Err(BuildError::InPlaceConflict { node: writer, reader: id });
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The texture an output is rendered into, once render targets are assigned.
+fn output_texture<T, U>(
+    graph: &Graph<T, U>,
+    passes: &[Pass<T>],
+    node_passes: &[i32],
+    output: NodeOutput,
+) -> Option<TextureId> {
+    let owner = graph.in_place_owner(output.node);
+    let node = &graph.nodes[owner.index()];
+    match (node.alloc_kind, output.index) {
+        (AllocKind::Fixed(texture, _), 0) | (AllocKind::External(texture, _), 0) => Some(texture),
+        _ => {
+            let pass = node_passes[owner.index()] as usize;
+            passes[pass].dynamic_targets[node.output_target_kind(output.index) as usize].destination
+        }
+    }
+}
+
+/// Add the in-place nodes to the targets of their passes that render into the texture
+/// of the node they render into.
+///
+/// This is the pass's dynamic target if it has the right destination, otherwise a target
+/// with that destination is added next to the fixed ones.
+fn place_in_place_nodes<T: Clone, U>(
+    graph: &Graph<T, U>,
+    passes: &mut [Pass<T>],
+    node_passes: &[i32],
+) -> Result<(), BuildError> {
+    for id in graph.node_ids() {
+        let node = &graph.nodes[id.index()];
+        let pass_index = node_passes[id.index()];
+        if !matches!(node.alloc_kind, AllocKind::InPlace(..)) || pass_index == i32::MAX {
+            continue;
+        }
+
+        let texture = output_texture(graph, passes, node_passes, NodeOutput::main(id)).unwrap();
+
+        let task = Task {
+            node_id: id,
+            output: 0,
+            task_id: node.task_id.clone(),
+        };
+        let pass = &mut passes[pass_index as usize];
+        let dynamic_target = &mut pass.dynamic_targets[node.target_kind as usize];
+        if dynamic_target.destination == Some(texture) {
+            dynamic_target.tasks.push(task);
+            continue;
+        }
+        match pass.fixed_targets.iter_mut().find(|target| target.destination == Some(texture)) {
+            Some(target) => target.tasks.push(task),
+            None => pass.fixed_targets.push(PassTarget {
+                tasks: vec![task],
+                destination: Some(texture),
+            }),
+        }
+    }
+
+    // Targets are chosen without looking at in-place nodes, so the pass of an in-place
+    // node may read from the texture it renders into. Apart from the nodes in-place
+    // nodes render into, this isn't allowed.
+    for id in graph.node_ids() {
+        if !matches!(graph.nodes[id.index()].alloc_kind, AllocKind::InPlace(..)) || node_passes[id.index()] == i32::MAX {
+            continue;
+        }
+        let texture = output_texture(graph, passes, node_passes, NodeOutput::main(id));
+        let pass = &passes[node_passes[id.index()] as usize];
+        for target in pass.dynamic_targets.iter().chain(pass.fixed_targets.iter()) {
+            for task in &target.tasks {
+                let in_place_input = match graph.nodes[task.node_id.index()].alloc_kind {
+                    AllocKind::InPlace(dep) => Some(NodeOutput::main(dep)),
+                    _ => None,
+                };
+                for input in graph.node_inputs(task.node_id) {
+                    if Some(input) != in_place_input && output_texture(graph, passes, node_passes, input) == texture {
+                        return Err(BuildError::InPlaceConflict { node: id, reader: task.node_id });
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
 /// Assign a render target to each pass with a "ping-pong" scheme alternating between
 /// two render targets.
 ///
 /// In order to ensure that a node never reads and writes from the same target, some
 /// copy nodes may be inserted in the graph.
-fn assign_targets_ping_pong(
-    graph: &mut Graph,
-    passes: &mut[Pass],
-    node_passes: &mut [i32],
-    allocator: &mut dyn TextureAllocator,
-) {
-    let mut node_redirects = vec![None; graph.nodes.len()];
-
+fn assign_targets_ping_pong<T: Clone, U>(
+    graph: &mut Graph<T, U>,
+    passes: &mut[Pass<T>],
+    node_passes: &mut Vec<i32>,
+    mut allocator: Option<&mut (dyn TextureAllocator + '_)>,
+    copy_tasks: &mut dyn CopyTaskFactory<T>,
+) -> Result<(), BuildError> {
+    let mut redirects = HashMap::new();
+
+    let mut next_texture = 0;
+    let mut add_texture = |kind| add_dynamic_texture(&mut allocator, &mut next_texture, kind);
     let texture_ids = [
         // color
         [
-            allocator.add_texture(),
-            allocator.add_texture(),
+            add_texture(TargetKind::Color),
+            add_texture(TargetKind::Color),
         ],
         // alpha
         [
-            allocator.add_texture(),
-            allocator.add_texture(),
+            add_texture(TargetKind::Alpha),
+            add_texture(TargetKind::Alpha),
         ],
     ];
 
@@ -463,8 +1361,10 @@ fn assign_targets_ping_pong(
         // alpha
         0,
     ];
+    let mut extra_textures = [None; NUM_TARGET_KINDS];
 
     for p in 0..passes.len() {
+        let mut destinations = [None; NUM_TARGET_KINDS];
         for target_kind_index in 0..NUM_TARGET_KINDS {
             if passes[p].dynamic_targets[target_kind_index].tasks.is_empty() {
                 continue;
@@ -473,65 +1373,158 @@ fn assign_targets_ping_pong(
             let ping_pong = nth_dynamic_pass[target_kind_index] % 2;
             nth_dynamic_pass[target_kind_index] += 1;
 
-            let current_destination = texture_ids[target_kind_index][ping_pong];
-            passes[p].dynamic_targets[target_kind_index].destination = Some(current_destination);
-
-            for nth_node in 0..passes[p].dynamic_targets[target_kind_index].tasks.len() {
-                let node = passes[p].dynamic_targets[target_kind_index].tasks[nth_node].node_id;
-                for dep_idx in 0..graph.nodes[node.index()].dependencies.len() {
-                    let dep = graph.nodes[node.index()].dependencies[dep_idx];
-                    let dep_pass = node_passes[dep.index()] as usize;
-                    let dep_target_kind = graph.nodes[dep.index()].target_kind;
-
-                    // Can't both read and write the same target.
-                    if passes[dep_pass].dynamic_targets[dep_target_kind as usize].destination == Some(current_destination) {
-                        graph.nodes[node.index()].dependencies[dep_idx] = handle_conflict_using_copy_task(
-                            graph,
-                            passes,
-                            &mut node_redirects,
-                            dep,
-                            dep_target_kind,
-                            p
-                        );
-                    }
+            let destination = texture_ids[target_kind_index][ping_pong];
+            passes[p].dynamic_targets[target_kind_index].destination = Some(destination);
+            destinations[target_kind_index] = Some(destination);
+        }
+
+        // No node of the pass can read from its dynamic targets, including the nodes of
+        // the fixed targets and the ones that only have additional outputs in them.
+        let mut pass_nodes = Vec::new();
+        for target in passes[p].dynamic_targets.iter().chain(passes[p].fixed_targets.iter()) {
+            for task in &target.tasks {
+                if !pass_nodes.contains(&task.node_id) {
+                    pass_nodes.push(task.node_id);
+                }
+            }
+        }
+
+        for node in pass_nodes {
+            let num_deps = graph.nodes[node.index()].dependencies.len();
+            let num_inputs = num_deps + graph.nodes[node.index()].output_dependencies.len();
+            for input_idx in 0..num_inputs {
+                let input = if input_idx < num_deps {
+                    NodeOutput::main(graph.nodes[node.index()].dependencies[input_idx])
+                } else {
+                    graph.nodes[node.index()].output_dependencies[input_idx - num_deps]
+                };
+                if graph.nodes[input.node.index()].is_external() {
+                    // External inputs are never in dynamic targets.
+                    continue;
+                }
+                // In-place nodes are read from the target of the node they render into.
+                let owner = graph.in_place_owner(input.node);
+                let input_pass = node_passes[owner.index()] as usize;
+                let input_target_kind = graph.nodes[owner.index()].output_target_kind(input.index);
+                let kind = input_target_kind as usize;
+
+                // Can't both read and write the same target.
+                let input_texture = passes[input_pass].dynamic_targets[kind].destination;
+                if input_texture.is_none() || input_texture != destinations[kind] {
+                    continue;
+                }
+
+                // The copy goes in the previous pass, which must come after the
+                // in-place node.
+                if owner != input.node && node_passes[input.node.index()] as usize + 1 >= p {
+                    return Err(BuildError::InPlaceConflict {
+                        node: input.node,
+                        reader: node,
+                    });
+                }
+                let copy = handle_conflict_using_copy_task(
+                    graph,
+                    passes,
+                    node_passes,
+                    &mut redirects,
+                    input,
+                    p,
+                    copy_tasks,
+                );
+                // The previous pass may not have rendered anything of this kind
+                // yet, in which case the copy goes to a texture the previous pass
+                // doesn't read from: the other texture if possible, otherwise an
+                // extra texture that is only used for such copies.
+                if passes[p - 1].dynamic_targets[kind].destination.is_none() {
+                    let other = if texture_ids[kind][0] == input_texture.unwrap() {
+                        texture_ids[kind][1]
+                    } else {
+                        texture_ids[kind][0]
+                    };
+                    let destination = if !pass_reads_texture(graph, passes, node_passes, p - 1, other) {
+                        other
+                    } else {
+                        match extra_textures[kind] {
+                            Some(extra) if !pass_reads_texture(graph, passes, node_passes, p - 1, extra) => extra,
+                            _ => {
+                                let extra = add_texture(TARGET_KINDS[kind]);
+                                extra_textures[kind] = Some(extra);
+                                extra
+                            }
+                        }
+                    };
+                    passes[p - 1].dynamic_targets[kind].destination = Some(destination);
+                }
+                let node = &mut graph.nodes[node.index()];
+                if input_idx < num_deps {
+                    node.dependencies[input_idx] = copy;
+                } else {
+                    node.output_dependencies[input_idx - num_deps] = NodeOutput::main(copy);
                 }
             }
         }
     }
+
+    Ok(())
+}
+
+/// Whether a node of the pass reads from the texture, once render targets are assigned
+/// to the passes up to this one.
+fn pass_reads_texture<T, U>(
+    graph: &Graph<T, U>,
+    passes: &[Pass<T>],
+    node_passes: &[i32],
+    pass_index: usize,
+    texture: TextureId,
+) -> bool {
+    let pass = &passes[pass_index];
+    pass.dynamic_targets.iter().chain(pass.fixed_targets.iter())
+        .flat_map(|target| target.tasks.iter())
+        .flat_map(|task| graph.node_inputs(task.node_id))
+        .any(|input| output_texture(graph, passes, node_passes, input) == Some(texture))
 }
 
-fn handle_conflict_using_copy_task(
-    graph: &mut Graph,
-    passes: &mut[Pass],
-    node_redirects: &mut[Option<NodeId>],
-    dep: NodeId,
-    dep_target_kind: TargetKind,
+fn handle_conflict_using_copy_task<T: Clone, U>(
+    graph: &mut Graph<T, U>,
+    passes: &mut[Pass<T>],
+    node_passes: &mut Vec<i32>,
+    redirects: &mut HashMap<NodeOutput, NodeId>,
+    source: NodeOutput,
     pass: usize,
+    copy_tasks: &mut dyn CopyTaskFactory<T>,
 ) -> NodeId {
     // See if we have already added a copy task to avoid the problem.
-    if let Some(source) = node_redirects[dep.index()] {
-        return source;
+    if let Some(&copy_id) = redirects.get(&source) {
+        return copy_id;
     }
 
     // Otherwise add a copy task.
     let copy_id = node_id(graph.nodes.len());
-    let size = graph.nodes[dep.index()].size;
-    let target_kind = graph.nodes[dep.index()].target_kind;
-    graph.nodes.push(Node {
-        task_id: TaskId::Copy,
-        dependencies: smallvec![dep],
-        alloc_kind: AllocKind::Dynamic,
-        size,
-        target_kind,
-    });
-    node_redirects[dep.index()] = Some(copy_id);
+    let source_node = &graph.nodes[source.node.index()];
+    let size = source_node.output_size(source.index);
+    let target_kind = source_node.output_target_kind(source.index);
+    let alloc_options = source_node.alloc_options;
+    let task_id = copy_tasks.create_copy_task(source, &source_node.task_id);
+    let mut copy = Node {
+        alloc_options,
+        .. Node::new(task_id.clone(), target_kind, size, AllocKind::Dynamic, &[])
+    };
+    if source.index == 0 {
+        copy.dependencies.push(source.node);
+    } else {
+        copy.output_dependencies.push(source);
+    }
+    graph.nodes.push(copy);
+    node_passes.push(pass as i32 - 1);
+    redirects.insert(source, copy_id);
 
     passes[pass - 1]
-        .dynamic_targets[dep_target_kind as usize]
+        .dynamic_targets[target_kind as usize]
         .tasks
         .push(Task {
             node_id: copy_id,
-            task_id: TaskId::Copy,
+            output: 0,
+            task_id,
         });
 
     copy_id
@@ -541,25 +1534,30 @@ fn handle_conflict_using_copy_task(
 ///
 /// This method may generate more render targets than assign_targets_ping_pong,
 /// however it doesn't add any extra copying operations.
-fn assign_targets_direct(
-    graph: &mut Graph,
-    passes: &mut[Pass],
+fn assign_targets_direct<T, U>(
+    graph: &mut Graph<T, U>,
+    passes: &mut[Pass<T>],
     node_passes: &mut [i32],
-    allocator: &mut dyn TextureAllocator,
+    mut allocator: Option<&mut (dyn TextureAllocator + '_)>,
 ) {
     let mut allocated_textures = [Vec::new(), Vec::new()];
     let mut dependencies = std::collections::HashSet::new();
+    let mut next_texture = 0;
 
     for p in 0..passes.len() {
         let pass = &passes[p];
 
         dependencies.clear();
-        for target in &pass.dynamic_targets {
+        for target in pass.dynamic_targets.iter().chain(pass.fixed_targets.iter()) {
             for task in &target.tasks {
-                for &dep in graph.node_dependencies(task.node_id) {
-                    let dep_pass = node_passes[dep.index()];
-                    let target_kind = graph.nodes[dep.index()].target_kind;
-                    if let Some(id) = passes[dep_pass as usize].dynamic_targets[target_kind as usize].destination {
+                for input in graph.node_inputs(task.node_id) {
+                    if graph.nodes[input.node.index()].is_external() {
+                        continue;
+                    }
+                    let owner = graph.in_place_owner(input.node);
+                    let input_pass = node_passes[owner.index()];
+                    let target_kind = graph.nodes[owner.index()].output_target_kind(input.index);
+                    if let Some(id) = passes[input_pass as usize].dynamic_targets[target_kind as usize].destination {
                         dependencies.insert(id);
                     }
                 }
@@ -580,7 +1578,7 @@ fn assign_targets_direct(
             }
 
             let destination = destination.unwrap_or_else(|| {
-                let id = allocator.add_texture();
+                let id = add_dynamic_texture(&mut allocator, &mut next_texture, TARGET_KINDS[target_kind_index]);
                 allocated_textures[target_kind_index].push(id);
                 id
             });
@@ -590,44 +1588,75 @@ fn assign_targets_direct(
     }
 }
 
+/// Create the next dynamic texture.
+///
+/// Dynamic texture ids are assigned by the builder rather than by the allocator so that
+/// they are deterministic and never collide with fixed texture ids. The texture is added
+/// to the allocator if there is one.
+fn add_dynamic_texture(
+    allocator: &mut Option<&mut (dyn TextureAllocator + '_)>,
+    next_texture: &mut usize,
+    kind: TargetKind,
+) -> TextureId {
+    let id = TextureId::dynamic(*next_texture);
+    *next_texture += 1;
+    if let Some(allocator) = allocator {
+        allocator.add_texture(id, kind);
+    }
+
+    id
+}
+
 /// Determine when is the first and last time that the sub-rect associated to the
 /// result of each node is needed and allocate portions of the render targets
 /// accordingly.
 /// This method computes the lifetime of each node and delegates the allocation
 /// logic to the TextureAllocator implementation.
-fn allocate_target_rects(
-    graph: &Graph,
-    passes: &[Pass],
-    allocated_rectangles: &mut[Rectangle],
+///
+/// If `deallocate_before_allocate` is true, inputs that are read for the last time in a
+/// pass are deallocated before the pass's allocations unless they live in a texture the
+/// pass renders into.
+fn allocate_target_rects<T, U>(
+    graph: &Graph<T, U>,
+    passes: &[Pass<T>],
+    deallocate_before_allocate: bool,
+    allocated_rectangles: &mut[NodeRectangles],
+    records: &mut Vec<AllocationRecord>,
     allocator: &mut dyn TextureAllocator,
 ) {
-    // The allocation ids we get from the texture allocator.
-    let mut alloc_ids = vec![None; graph.nodes.len()];
-
     let mut visited = vec![false; graph.nodes.len()];
     let mut last_node_refs: Vec<NodeId> = Vec::with_capacity(graph.nodes.len());
     let mut pass_last_node_ranges: Vec<std::ops::Range<usize>> = vec![0..0; passes.len()];
 
     // The first step is to find for each pass the list of nodes that are not referenced
-    // anymore after the pass ends.
+    // anymore after the pass ends. All of the outputs of a node are kept alive until the
+    // last time any of them is read.
 
     // Mark roots as visited to avoid deallocating their target rects.
     for root in &graph.roots {
-        visited[root.index()] = true;
+        visited[graph.in_place_owner(*root).index()] = true;
     }
 
     // Visit passes in reverse order and look at the dependencies.
     // Each dependency that we haven't visited yet is the last reference to a node.
+    // Nodes that aren't visited by the time we reach their own pass are side effects
+    // that nothing reads, so they are not needed after it.
     let mut pass_index = passes.len();
     for pass in passes.iter().rev() {
         pass_index -= 1;
         let first = last_node_refs.len();
-        for target_kind in 0..NUM_TARGET_KINDS {
-            for task in &pass.dynamic_targets[target_kind].tasks {
-                for &dep in graph.node_dependencies(task.node_id) {
-                    let dep_idx = dep.index();
-                    if !visited[dep_idx] {
-                        visited[dep_idx] = true;
+        for target in pass.dynamic_targets.iter().chain(pass.fixed_targets.iter()) {
+            for task in &target.tasks {
+                let node = graph.in_place_owner(task.node_id);
+                if !visited[node.index()] {
+                    visited[node.index()] = true;
+                    last_node_refs.push(node);
+                }
+                for input in graph.node_inputs(task.node_id) {
+                    // Reading an in-place node keeps the allocation it renders into alive.
+                    let dep = graph.in_place_owner(input.node);
+                    if !visited[dep.index()] {
+                        visited[dep.index()] = true;
                         last_node_refs.push(dep);
                     }
                 }
@@ -636,9 +1665,34 @@ fn allocate_target_rects(
         pass_last_node_ranges[pass_index] = first..last_node_refs.len();
     }
 
-    // In the second step we go through each pass in order and perform allocations/deallocations.
+    // In the second step we go through each pass in order and record the allocations and
+    // deallocations to perform.
+    // The dynamically allocated outputs of each node and their texture.
+    let mut node_textures: Vec<SmallVec<[(u32, TextureId); 1]>> = vec![SmallVec::new(); graph.nodes.len()];
+    let mut events = Vec::new();
+    let mut record_indices: HashMap<NodeOutput, usize> = HashMap::new();
     for (pass_index, pass) in passes.iter().enumerate() {
-        for pass_target in &pass.dynamic_targets {
+        if deallocate_before_allocate {
+            // Deallocations we can perform before this pass. Nodes can't read and write
+            // the same texture, so inputs that don't live in any of the pass's destinations
+            // can be freed before allocating the pass's outputs.
+            let finished_range = pass_last_node_ranges[pass_index].clone();
+            for &finished_node in &last_node_refs[finished_range] {
+                node_textures[finished_node.index()].retain(|&mut (output, texture)| {
+                    let is_destination = pass.dynamic_targets.iter()
+                        .chain(pass.fixed_targets.iter())
+                        .any(|target| target.destination == Some(texture));
+                    if !is_destination {
+                        let output = NodeOutput::new(finished_node, output);
+                        events.push(AllocEvent::Deallocate(output, texture));
+                        records[record_indices[&output]].deallocated = Some(pass_index);
+                    }
+                    is_destination
+                });
+            }
+        }
+
+        for pass_target in pass.dynamic_targets.iter().chain(pass.fixed_targets.iter()) {
             if pass_target.tasks.is_empty() {
                 continue;
             }
@@ -648,167 +1702,887 @@ fn allocate_target_rects(
             for task in &pass_target.tasks {
                 let node_idx = task.node_id.index();
                 let node = &graph.nodes[node_idx];
-                let size = graph.nodes[node_idx].size;
-                allocated_rectangles[node_idx] = match node.alloc_kind {
-                    AllocKind::Dynamic => {
-                        let alloc = allocator.allocate(texture, size);
-                        alloc_ids[node_idx] = Some(alloc.id);
-                        alloc.rectangle
+                match (node.alloc_kind, task.output) {
+                    (AllocKind::Fixed(_, origin), 0) => {
+                        allocated_rectangles[node_idx][0] = Rectangle {
+                            min: origin,
+                            max: origin + node.alloc_options.padded_size(node.size).to_vector(),
+                        };
                     }
-                    AllocKind::Fixed(_, origin) => Rectangle {
-                        min: origin,
-                        max: origin + size.to_vector(),
+                    (AllocKind::InPlace(..), _) => {}
+                    _ => {
+                        let output = NodeOutput::new(task.node_id, task.output);
+                        let size = node.alloc_options.allocation_size(node.output_size(task.output));
+                        events.push(AllocEvent::Allocate(output, texture, size));
+                        node_textures[node_idx].push((task.output, texture));
+                        record_indices.insert(output, records.len());
+                        records.push(AllocationRecord {
+                            output,
+                            texture,
+                            allocated: pass_index,
+                            deallocated: None,
+                        });
                     }
-                };
+                }
             }
         }
 
         // Deallocations we can perform after this pass.
         let finished_range = pass_last_node_ranges[pass_index].clone();
-        for finished_node in &last_node_refs[finished_range] {
-            let node_idx = finished_node.index();
-            if let Some(alloc_id) = alloc_ids[node_idx] {
-                allocator.deallocate(alloc_id);
+        for &finished_node in &last_node_refs[finished_range] {
+            for (output, texture) in node_textures[finished_node.index()].drain(..) {
+                let output = NodeOutput::new(finished_node, output);
+                events.push(AllocEvent::Deallocate(output, texture));
+                records[record_indices[&output]].deallocated = Some(pass_index + 1);
             }
         }
     }
-}
 
-pub fn build_and_print_graph(graph: &Graph, options: BuilderOptions, with_deallocations: bool) {
-    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
-    let mut allocator = DbgTextureAllocator::new(&mut allocator);
-    allocator.record_deallocations = with_deallocations;
+    // Finally, perform the allocations. Each texture is independent from the others so
+    // they can be processed in parallel if the allocator supports it.
+    let mut output = |output: NodeOutput, allocation: Rectangle| {
+        let node = &graph.nodes[output.node.index()];
+        let size = node.output_size(output.index);
+        allocated_rectangles[output.node.index()][output.index as usize] = node.alloc_options.padded_rectangle(&allocation, size);
+    };
+
+    #[cfg(feature = "rayon")]
+    {
+        if let Some(shards) = allocator.texture_shards() {
+            apply_allocation_events_parallel(&events, shards, &mut output);
+            return;
+        }
+    }
 
-    let built_graph = graph.clone().build(&options, &mut allocator);
+    apply_allocation_events(&events, allocator, &mut output);
+}
 
-    let n_passes = built_graph.passes.len();
-    let mut n_nodes = 0;
-    for pass in &built_graph.passes {
-        for target in &pass.dynamic_targets {
-            n_nodes += target.tasks.len();
+/// The size of each dynamic texture used by the passes, indexed by texture index.
+///
+/// With planned allocations the textures are as large as the extents of their
+/// rectangles, otherwise the size comes from the allocator when it knows it.
+fn dynamic_texture_sizes<T>(
+    passes: &[Pass<T>],
+    allocated_rectangles: &[NodeRectangles],
+    allocator: &dyn TextureAllocator,
+    planned: bool,
+) -> Vec<Size> {
+    let mut rectangles: Vec<Vec<Rectangle>> = Vec::new();
+    for pass in passes {
+        for target in pass.dynamic_targets.iter().chain(pass.fixed_targets.iter()) {
+            let texture = match target.destination {
+                Some(texture) if texture.is_dynamic() => texture,
+                _ => continue,
+            };
+            if rectangles.len() <= texture.index() {
+                rectangles.resize(texture.index() + 1, Vec::new());
+            }
+            for task in &target.tasks {
+                rectangles[texture.index()].push(allocated_rectangles[task.node_id.index()][task.output as usize]);
+            }
         }
-        for target in &pass.fixed_targets {
-            n_nodes += target.tasks.len();
+    }
+
+    rectangles.iter().enumerate().map(|(idx, rects)| {
+        let size = if planned { None } else { allocator.texture_size(TextureId::dynamic(idx)) };
+        size.unwrap_or_else(|| extents(rects))
+    }).collect()
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum AllocEvent {
+    /// Allocate a rectangle of the given size (including padding and border) for an output.
+    Allocate(NodeOutput, TextureId, Size),
+    Deallocate(NodeOutput, TextureId),
+}
+
+/// Perform the allocations and deallocations, passing the rectangles returned by the
+/// allocator to `output`.
+fn apply_allocation_events(
+    events: &[AllocEvent],
+    allocator: &mut dyn TextureAllocator,
+    output: &mut dyn FnMut(NodeOutput, Rectangle),
+) {
+    // The allocation ids we get from the texture allocator.
+    let mut alloc_ids: HashMap<NodeOutput, AllocId> = HashMap::new();
+
+    for event in events {
+        match *event {
+            AllocEvent::Allocate(node_output, texture, size) => {
+                let alloc = allocator.allocate(texture, size);
+                alloc_ids.insert(node_output, alloc.id);
+                output(node_output, alloc.rectangle);
+            }
+            AllocEvent::Deallocate(node_output, _) => {
+                allocator.deallocate(alloc_ids.remove(&node_output).unwrap());
+            }
         }
     }
+}
+
+#[cfg(feature = "rayon")]
+fn apply_allocation_events_parallel(
+    events: &[AllocEvent],
+    shards: Vec<AtlasShard>,
+    output: &mut dyn FnMut(NodeOutput, Rectangle),
+) {
+    use rayon::prelude::*;
+
+    let mut shard_indices = HashMap::new();
+    let mut work = Vec::with_capacity(shards.len());
+    for (idx, shard) in shards.into_iter().enumerate() {
+        shard_indices.insert(shard.texture, idx);
+        work.push((shard, Vec::new()));
+    }
+
+    for event in events {
+        let texture = match *event {
+            AllocEvent::Allocate(_, texture, _) => texture,
+            AllocEvent::Deallocate(_, texture) => texture,
+        };
+        let idx = shard_indices[&texture];
+        work[idx].1.push(*event);
+    }
+
+    let results: Vec<Vec<(NodeOutput, Rectangle)>> = work.into_par_iter().map(|(mut shard, events)| {
+        let mut rectangles = Vec::with_capacity(events.len());
+        apply_allocation_events(&events, &mut shard, &mut |node, rect| {
+            rectangles.push((node, rect));
+        });
+        rectangles
+    }).collect();
+
+    for (node, rect) in results.into_iter().flatten() {
+        output(node, rect);
+    }
+}
+
+/// Build the graph with the textures of `pool` and print the result.
+///
+/// Calling it once per frame with the same pool reuses the textures of the previous
+/// frames, see `TexturePool`.
+pub fn build_and_print_graph<U: Clone>(
+    graph: &Graph<TaskId, U>,
+    options: BuilderOptions,
+    with_deallocations: bool,
+    pool: &mut TexturePool,
+) {
+    pool.begin_frame();
+    let mut allocator = DbgTextureAllocator::new(pool);
+    allocator.record_deallocations = with_deallocations;
+
+    let built_graph = graph.clone().build(&options, &mut allocator).unwrap();
 
     println!(
-        "\n\n------------- deallocations: {:?}, targets: {:?}",
+        "\n\n------------- deallocations: {:?}, targets: {:?}, allocations: {:?}",
         with_deallocations,
-        options.targets
+        options.targets,
+        options.allocations,
     );
     println!(
-        "              {:?} nodes, {:?} passes, {:?} targets, {:?} pixels (max), {:?} rects (max)",
-        n_nodes,
-        n_passes,
+        "              {:?} targets, {:?} pixels (max), {:?} rects (max)",
         allocator.textures.len(),
         allocator.max_allocated_pixels(),
         allocator.max_allocated_rects(),
     );
+    let stats = pool.stats();
+    println!(
+        "              {:?} pooled textures, {:?} reused, {:?} created, {:?} evicted",
+        pool.textures.len(),
+        stats.hits,
+        stats.misses,
+        stats.evictions,
+    );
+    print!("{}", built_graph.verbose());
+}
+
+#[test]
+fn allocation_options() {
+    let mut graph = Graph::new();
+
+    let options = AllocationOptions {
+        padding: 4,
+        border: 2,
+        alignment: 16,
+    };
+
+    let n0 = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 50), AllocKind::Dynamic, &[]);
+    let n1 = graph.add_node(TaskId::Render(0, 1), TargetKind::Color, size2(30, 70), AllocKind::Dynamic, &[]);
+    let n2 = graph.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(10, 10), AllocKind::Dynamic, &[]);
+    let root = graph.add_node(TaskId::Render(0, 3), TargetKind::Color, size2(800, 600), AllocKind::Fixed(TextureId(100), point2(10, 10)), &[n0, n1, n2]);
+    graph.set_allocation_options(n0, options);
+    graph.set_allocation_options(n1, options);
+    graph.set_allocation_options(root, AllocationOptions { padding: 2, .. AllocationOptions::default() });
+    graph.add_root(root);
+
+    for &targets in &[TargetOptions::Direct, TargetOptions::PingPong] {
+        let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+        let built = graph.clone().build(&BuilderOptions { targets, .. BuilderOptions::default() }, &mut allocator).unwrap();
+
+        for &(node, size) in &[(n0, size2(100, 50)), (n1, size2(30, 70))] {
+            let allocated = *built.allocated_rectangle(node);
+            assert_eq!(allocated.size(), size + size2(8, 8));
+            assert_eq!(built.inner_rectangle(node).size(), size);
+            assert_eq!(built.inner_rectangle(node).min, allocated.min + vec2(4, 4));
+            assert_eq!(allocated.min.x % 16, 0);
+            assert_eq!(allocated.min.y % 16, 0);
+        }
+
+        let r0 = built.allocated_rectangle(n0).inflate(2, 2);
+        let r1 = built.allocated_rectangle(n1).inflate(2, 2);
+        let r2 = *built.allocated_rectangle(n2);
+        assert!(!r0.intersects(&r1));
+        assert!(!r0.intersects(&r2));
+        assert!(!r1.intersects(&r2));
+        assert_eq!(r2.size(), size2(10, 10));
+
+        assert_eq!(*built.allocated_rectangle(root), Rectangle { min: point2(10, 10), max: point2(814, 614) });
+        assert_eq!(built.inner_rectangle(root), Rectangle { min: point2(12, 12), max: point2(812, 612) });
+    }
+
+    for &invalid in &[
+        AllocationOptions { padding: -1, .. options },
+        AllocationOptions { border: -1, .. options },
+        AllocationOptions { alignment: -16, .. options },
+    ] {
+        let mut graph = graph.clone();
+        graph.set_allocation_options(n1, invalid);
+        let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+        assert_eq!(
+            graph.build(&BuilderOptions::default(), &mut allocator).err(),
+            Some(BuildError::InvalidAllocationOptions(n1)),
+        );
+    }
+}
+
+#[test]
+fn planned_allocations() {
+    let mut graph = Graph::new();
+
+    let mut prev = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(300, 200), AllocKind::Dynamic, &[]);
+    let mut inputs = vec![prev];
+    for i in 1..12 {
+        let size = size2(50 + (i * 37) % 200, 40 + (i * 53) % 150);
+        let node = graph.add_node(TaskId::Render(0, i as u32), TargetKind::Color, size, AllocKind::Dynamic, &[prev]);
+        if i % 3 == 0 {
+            inputs.push(node);
+        }
+        prev = node;
+    }
+    let root = graph.add_node(TaskId::Render(1, 0), TargetKind::Color, size2(1000, 1000), AllocKind::Fixed(TextureId(123), point2(0, 0)), &inputs);
+    graph.add_root(root);
 
-    for i in 0..built_graph.passes.len() {
-        let pass = &built_graph.passes[i];
-        println!("# pass {:?}", i);
-        for &target_kind in &[TargetKind::Color, TargetKind::Alpha] {
-            if let Some(texture) = pass.dynamic_targets[target_kind as usize].destination {
-                println!("  * Dynamic {:?} target {:?}:", target_kind, texture);
-                for task in &pass.dynamic_targets[target_kind as usize].tasks {
-                    let r = built_graph.allocated_rectangle(task.node_id);
-                    println!("     - {:?} {:?}      rect: [({}, {}) {}x{}]",
-                        task.node_id,
-                        built_graph.nodes[task.node_id.index()].task_id,
-                        r.min.x, r.min.y, r.size().width, r.size().height,
-                    );
+    for &targets in &[TargetOptions::Direct, TargetOptions::PingPong] {
+        let options = BuilderOptions {
+            targets,
+            allocations: AllocationStrategy::Planned,
+            .. BuilderOptions::default()
+        };
+        let mut guillotine = GuillotineAllocator::new(size2(1024, 1024));
+        let mut allocator = DbgTextureAllocator::new(&mut guillotine);
+        let built = graph.clone().build(&options, &mut allocator).unwrap();
+        // The allocator isn't used at all.
+        assert!(allocator.textures.is_empty());
+
+        let lifetimes = crate::planner::node_lifetimes(&built.graph, &built.passes);
+        let mut allocations = Vec::new();
+        for pass in &built.passes {
+            for target in &pass.dynamic_targets {
+                for task in &target.tasks {
+                    allocations.push((
+                        target.destination.unwrap(),
+                        *built.allocated_rectangle(task.node_id),
+                        lifetimes[task.node_id.index()].unwrap(),
+                    ));
                 }
             }
         }
 
-        for target in &pass.fixed_targets {
-            println!("  * Fixed target {:?}:", target.destination.unwrap());
-            for task in &target.tasks {
-                let r = built_graph.allocated_rectangle(task.node_id);
-                println!("     - {:?} {:?}      rect: [({}, {}) {}x{}]",
-                    task.node_id,
-                    built_graph.nodes[task.node_id.index()].task_id,
-                    r.min.x, r.min.y, r.size().width, r.size().height,
-                );
+        for (i, a) in allocations.iter().enumerate() {
+            for b in &allocations[i + 1..] {
+                if a.0 == b.0 && a.2.overlaps(&b.2) {
+                    assert!(!a.1.intersects(&b.1), "{:?} and {:?} overlap", a, b);
+                }
             }
         }
+
+        let planned = built.memory_stats();
+        let pass_by_pass = graph.clone().build(
+            &BuilderOptions { targets, .. BuilderOptions::default() },
+            &mut GuillotineAllocator::new(size2(1024, 1024)),
+        ).unwrap().memory_stats();
+
+        assert_eq!(planned.textures, pass_by_pass.textures);
+        assert_eq!(planned.live_pixels, pass_by_pass.live_pixels);
+        assert!(planned.texture_pixels >= planned.live_pixels);
+        assert!(
+            planned.texture_pixels < pass_by_pass.texture_pixels,
+            "planned: {:?}, pass by pass: {:?}", planned, pass_by_pass,
+        );
+    }
+}
+
+#[test]
+fn planned_in_place_root() {
+    // The allocation of the picture is kept until the end since the in-place node
+    // rendering into it is a root.
+    let mut graph = Graph::new();
+    let picture = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let overlay = graph.add_node(TaskId::Render(0, 1), TargetKind::Color, size2(100, 100), AllocKind::InPlace(picture), &[picture]);
+    let root = graph.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(400, 400), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[overlay]);
+    graph.add_root(overlay);
+    graph.add_root(root);
+
+    for &allocations in &[AllocationStrategy::PassByPass, AllocationStrategy::Planned] {
+        let options = BuilderOptions { allocations, .. BuilderOptions::default() };
+        let built = graph.clone().build(&options, &mut GuillotineAllocator::new(size2(1024, 1024))).unwrap();
+        let record = built.allocations().iter().find(|record| record.output == NodeOutput::main(picture)).unwrap();
+        assert_eq!(record.deallocated, None);
     }
 }
 
 #[test]
-fn simple_graph() {
+fn dynamic_texture_ids() {
     let mut graph = Graph::new();
 
     let n0 = graph.add_node(TaskId::Render(0, 0), TargetKind::Alpha, size2(100, 100), AllocKind::Dynamic, &[]);
-    let _ = graph.add_node(TaskId::Render(0, 1), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n0]);
-    let n2 = graph.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
-    let n3 = graph.add_node(TaskId::Render(0, 3), TargetKind::Alpha, size2(100, 100), AllocKind::Dynamic, &[]);
-    let n4 = graph.add_node(TaskId::Render(0, 4), TargetKind::Alpha, size2(100, 100), AllocKind::Dynamic, &[n2, n3]);
-    let n5 = graph.add_node(TaskId::Render(0, 5), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
-    let n6 = graph.add_node(TaskId::Render(0, 6), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n3, n5]);
-    let n7 = graph.add_node(TaskId::Render(0, 7), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n2, n4, n6]);
-    let n8 = graph.add_node(TaskId::Render(0, 8), TargetKind::Color, size2(800, 600), AllocKind::Fixed(TextureId(100), point2(0, 0)), &[n7]);
-
-    graph.add_root(n5);
-    graph.add_root(n8);
-
-    for &with_deallocations in &[true] {
-        for &target_option in &[TargetOptions::Direct, TargetOptions::PingPong] {
-            build_and_print_graph(
-                &graph,
-                BuilderOptions {
-                    targets: target_option,
-                },
-                with_deallocations,
-            )
+    let n1 = graph.add_node(TaskId::Render(0, 1), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n0]);
+    let n2 = graph.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(100, 100), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[n1]);
+    graph.add_root(n2);
+
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    // Pretend the allocator was used before.
+    allocator.add_texture(TextureId::dynamic(5), TargetKind::Color);
+
+    let built = graph.clone().build(&BuilderOptions::default(), &mut allocator).unwrap();
+    let mut textures = Vec::new();
+    for pass in built.passes() {
+        for target in pass.dynamic_targets.iter().chain(pass.fixed_targets.iter()) {
+            if !target.tasks.is_empty() {
+                textures.push(target.destination.unwrap());
+            }
+        }
+    }
+    assert_eq!(textures, vec![TextureId::dynamic(0), TextureId::dynamic(1), TextureId(0)]);
+    assert!(!TextureId(0).is_dynamic());
+    assert_eq!(TextureId::dynamic(1).index(), 1);
+
+    let mut graph = graph.clone();
+    let n3 = graph.add_node(TaskId::Render(0, 3), TargetKind::Color, size2(100, 100), AllocKind::Fixed(TextureId::dynamic(0), point2(0, 0)), &[n1]);
+    graph.add_root(n3);
+    let result = graph.build(&BuilderOptions::default(), &mut allocator);
+    assert_eq!(result.err(), Some(BuildError::ReservedTextureId(n3, TextureId::dynamic(0))));
+}
+
+#[test]
+fn deallocate_before_allocate() {
+    let mut graph = Graph::new();
+
+    let n0 = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let n1 = graph.add_node(TaskId::Render(0, 1), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n0]);
+    let n2 = graph.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n1]);
+    let n3 = graph.add_node(TaskId::Render(0, 3), TargetKind::Color, size2(800, 600), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[n2]);
+    graph.add_root(n3);
+
+    let mut max_pixels = Vec::new();
+    for &deallocate_before_allocate in &[false, true] {
+        let mut guillotine = GuillotineAllocator::new(size2(1024, 1024));
+        let mut allocator = DbgTextureAllocator::new(&mut guillotine);
+        let options = BuilderOptions { deallocate_before_allocate, .. BuilderOptions::default() };
+        graph.clone().build(&options, &mut allocator).unwrap();
+        max_pixels.push(allocator.max_allocated_pixels());
+    }
+
+    assert_eq!(max_pixels, vec![20000, 10000]);
+}
+
+// Without rayon both builds take the same path.
+#[cfg(feature = "rayon")]
+#[test]
+fn sharded_allocations() {
+    let mut graph = Graph::new();
+
+    let mut layer = Vec::new();
+    for i in 0..6 {
+        let kind = if i % 2 == 0 { TargetKind::Color } else { TargetKind::Alpha };
+        layer.push(graph.add_node(TaskId::Render(0, i), kind, size2(50 + i as i32 * 10, 80), AllocKind::Dynamic, &[]));
+    }
+    for depth in 1..5 {
+        let mut next = Vec::new();
+        for i in 0..layer.len() - 1 {
+            let kind = if (i + depth) % 2 == 0 { TargetKind::Color } else { TargetKind::Alpha };
+            let size = size2(20 + (i * 37 + depth * 11) as i32 % 200, 30 + (i * 53) as i32 % 150);
+            next.push(graph.add_node(TaskId::Render(depth as u16, i as u32), kind, size, AllocKind::Dynamic, &[layer[i], layer[i + 1]]));
+        }
+        layer = next;
+    }
+    let root = graph.add_node(TaskId::Render(9, 0), TargetKind::Color, size2(800, 600), AllocKind::Fixed(TextureId(0), point2(0, 0)), &layer);
+    graph.add_root(root);
+
+    for &targets in &[TargetOptions::Direct, TargetOptions::PingPong] {
+        for &deallocate_before_allocate in &[false, true] {
+            let options = BuilderOptions { targets, deallocate_before_allocate, .. BuilderOptions::default() };
+
+            // GuillotineAllocator can be split into per-texture shards.
+            let mut sharded = GuillotineAllocator::new(size2(256, 256));
+            let a = graph.clone().build(&options, &mut sharded).unwrap();
+
+            // DbgTextureAllocator can't.
+            let mut guillotine = GuillotineAllocator::new(size2(256, 256));
+            let b = graph.clone().build(&options, &mut DbgTextureAllocator::new(&mut guillotine)).unwrap();
+
+            assert_eq!(a.num_nodes(), b.num_nodes());
+            assert_eq!(a.allocated_rectangles, b.allocated_rectangles);
+            assert_eq!(a.passes.len(), b.passes.len());
+            for (pa, pb) in a.passes.iter().zip(b.passes.iter()) {
+                for (ta, tb) in pa.dynamic_targets.iter().chain(pa.fixed_targets.iter())
+                    .zip(pb.dynamic_targets.iter().chain(pb.fixed_targets.iter())) {
+                    assert_eq!(ta.destination, tb.destination);
+                    assert_eq!(ta.tasks, tb.tasks);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn labels_and_user_data() {
+    // Not Clone, Send or Sync.
+    struct DrawData(std::rc::Rc<u32>);
+
+    let mut graph: Graph<TaskId, DrawData> = Graph::default();
+    let n0 = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let n1 = graph.add_node(TaskId::Render(0, 1), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n0]);
+    let n2 = graph.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n1]);
+    graph.add_dependency(n2, n0);
+    graph.add_root(n2);
+
+    graph.set_label(n0, "picture".to_string());
+    graph.set_user_data(n1, DrawData(std::rc::Rc::new(1)));
+
+    assert_eq!(graph.label(n0), Some("picture"));
+    assert_eq!(graph.label(n1), None);
+    assert_eq!(graph.node_name(n0), "picture");
+    assert_eq!(graph.node_name(n1), "NodeId(1)");
+    assert!(graph.user_data(n0).is_none());
+    assert!(graph.user_data(n2).is_none());
+    *std::rc::Rc::get_mut(&mut graph.user_data_mut(n1).unwrap().0).unwrap() += 1;
+
+    // n0 is read and written in the same target with ping-pong, which inserts a copy task.
+    let options = BuilderOptions { targets: TargetOptions::PingPong, .. BuilderOptions::default() };
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    let built = graph.build(&options, &mut allocator).unwrap();
+
+    assert_eq!(built.label(n0), Some("picture"));
+    assert_eq!(*built.user_data(n1).unwrap().0, 2);
+    for id in built.node_ids().skip(3) {
+        assert!(built.user_data(id).is_none());
+    }
+}
+
+#[test]
+fn custom_task_type() {
+    // Not Copy.
+    #[derive(Clone, Debug, PartialEq)]
+    enum EngineTask {
+        Picture(String),
+        Blur(f32),
+        Copy(NodeId),
+    }
+
+    let mut graph: Graph<EngineTask> = Graph::default();
+    let n0 = graph.add_node(EngineTask::Picture("image".to_string()), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let n1 = graph.add_node(EngineTask::Blur(2.0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n0]);
+    let n2 = graph.add_node(EngineTask::Blur(4.0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n1, n0]);
+    graph.add_root(n2);
+
+    let options = BuilderOptions { targets: TargetOptions::PingPong, .. BuilderOptions::default() };
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    let mut copies = Vec::new();
+    let built = graph.build_with_copy_tasks(&options, &mut allocator, &mut |source: NodeOutput, task: &EngineTask| {
+        copies.push(task.clone());
+        EngineTask::Copy(source.node)
+    }).unwrap();
+
+    // n2 reads n0, which is in the same ping-pong target.
+    assert_eq!(copies, vec![EngineTask::Picture("image".to_string())]);
+    assert_eq!(built.num_nodes(), 4);
+    let copy = node_id(3);
+    assert_eq!(built[copy].task_id, EngineTask::Copy(n0));
+    assert_eq!(built.node_dependencies(n2), &[n1, copy]);
+
+    let copy_pass = built.passes().iter().position(|pass| {
+        pass.dynamic_targets[0].tasks.iter().any(|task| task.node_id == copy)
+    }).unwrap();
+    let task = built.passes()[copy_pass].dynamic_targets[0].tasks.iter().find(|task| task.node_id == copy).unwrap();
+    assert_eq!(task.task_id, EngineTask::Copy(n0));
+}
+
+#[test]
+fn multiple_outputs() {
+    // A picture rendered along with its mask, the mask being read by a blur and the
+    // picture by the final composite.
+    let mut graph = Graph::new();
+    let picture = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(200, 100), AllocKind::Dynamic, &[]);
+    let mask = graph.add_output(picture, TargetKind::Alpha, size2(100, 50));
+    assert_eq!(mask, NodeOutput::new(picture, 1));
+    let blur = graph.add_node(TaskId::Render(0, 1), TargetKind::Alpha, size2(100, 50), AllocKind::Dynamic, &[]);
+    graph.add_output_dependency(blur, mask);
+    let other = graph.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(300, 300), AllocKind::Dynamic, &[]);
+    let composite = graph.add_node(TaskId::Render(0, 3), TargetKind::Color, size2(400, 400), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[picture, blur, other]);
+    graph.add_root(composite);
+
+    assert_eq!(
+        graph.node_inputs(blur).collect::<Vec<_>>(),
+        vec![mask],
+    );
+
+    for &targets in &[TargetOptions::Direct, TargetOptions::PingPong] {
+        for &allocations in &[AllocationStrategy::PassByPass, AllocationStrategy::Planned] {
+            let options = BuilderOptions { targets, allocations, .. BuilderOptions::default() };
+            let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+            let built = graph.clone().build(&options, &mut allocator).unwrap();
+
+            // The picture writes into the color and alpha targets of its pass.
+            let pass = &built.passes()[0];
+            assert!(pass.dynamic_targets[TargetKind::Color as usize].tasks.iter().any(|task| task.node_id == picture && task.output == 0));
+            assert!(pass.dynamic_targets[TargetKind::Alpha as usize].tasks.iter().any(|task| task.node_id == picture && task.output == 1));
+
+            assert_eq!(built.allocated_rectangle(picture).size(), size2(200, 100));
+            assert_eq!(built.output_rectangle(mask).size(), size2(100, 50));
+
+            // The blur must not write into the texture it reads the mask from.
+            let mask_texture = pass.dynamic_targets[TargetKind::Alpha as usize].destination;
+            for pass in built.passes() {
+                for target in &pass.dynamic_targets {
+                    for task in &target.tasks {
+                        if built.node_inputs(task.node_id).any(|input| input == mask) {
+                            assert_ne!(target.destination, mask_texture);
+                        }
+                    }
+                }
+            }
         }
     }
 }
 
 #[test]
-fn test_stacked_shadows() {
+fn external_inputs() {
     let mut graph = Graph::new();
+    let video = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(640, 480), AllocKind::External(TextureId(1), point2(0, 0)), &[]);
+    let previous = graph.add_node(TaskId::Render(0, 1), TargetKind::Color, size2(100, 100), AllocKind::External(TextureId(0), point2(500, 500)), &[]);
+    let _unused = graph.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(100, 100), AllocKind::External(TextureId(2), point2(0, 0)), &[]);
+    let n0 = graph.add_node(TaskId::Render(1, 0), TargetKind::Color, size2(320, 240), AllocKind::Dynamic, &[video]);
+    let n1 = graph.add_node(TaskId::Render(1, 1), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n0, previous]);
+    let root = graph.add_node(TaskId::Render(1, 2), TargetKind::Color, size2(400, 400), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[n1, video]);
+    graph.add_root(root);
+
+    for &targets in &[TargetOptions::Direct, TargetOptions::PingPong] {
+        let options = BuilderOptions { targets, .. BuilderOptions::default() };
+        let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+        let built = graph.clone().build(&options, &mut allocator).unwrap();
+
+        // Inputs are not part of any pass and no empty pass is created for them.
+        assert_eq!(built.inputs(), &[video, previous]);
+        assert_eq!(built.passes().len(), 3);
+        for pass in built.passes() {
+            for target in pass.dynamic_targets.iter().chain(pass.fixed_targets.iter()) {
+                assert!(target.tasks.iter().all(|task| !built[task.node_id].is_external()));
+            }
+        }
+        assert_eq!(
+            *built.allocated_rectangle(previous),
+            Rectangle { min: point2(500, 500), max: point2(600, 600) },
+        );
+    }
+
+    // The root is rendered after the last read of the previous frame's output, so it
+    // can overwrite it. It can't if it also reads it.
+    let mut overwritten = graph.clone();
+    overwritten.nodes[root.index()].size = size2(550, 550);
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    assert!(overwritten.clone().build(&BuilderOptions::default(), &mut allocator).is_ok());
+    overwritten.add_dependency(root, previous);
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    assert_eq!(
+        overwritten.build(&BuilderOptions::default(), &mut allocator).err(),
+        Some(BuildError::ExternalInputOverwritten { node: root, input: previous }),
+    );
+
+    // External inputs can't have dependencies.
+    let mut invalid = graph.clone();
+    invalid.add_dependency(previous, n0);
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    assert_eq!(
+        invalid.build(&BuilderOptions::default(), &mut allocator).err(),
+        Some(BuildError::InvalidExternalInput(previous)),
+    );
+
+    // A pass can't write into the texture of an input it reads, even elsewhere in the
+    // texture. The sibling is in the same pass as n1, which reads the previous frame.
+    for &in_place in &[false, true] {
+        let mut same_pass = graph.clone();
+        let sibling = if in_place {
+            let fixed = same_pass.add_node(TaskId::Render(2, 0), TargetKind::Color, size2(50, 50), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[]);
+            let sibling = same_pass.add_node(TaskId::Render(2, 1), TargetKind::Color, size2(50, 50), AllocKind::InPlace(fixed), &[fixed]);
+            same_pass.add_side_effect(fixed);
+            sibling
+        } else {
+            same_pass.add_node(TaskId::Render(2, 0), TargetKind::Color, size2(50, 50), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[])
+        };
+        same_pass.add_dependency(root, sibling);
+        let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+        assert_eq!(
+            same_pass.build(&BuilderOptions::default(), &mut allocator).err(),
+            Some(BuildError::ExternalInputOverwritten { node: sibling, input: previous }),
+        );
+    }
+}
 
-    const PICTURE: u16 = 0;
-    const DOWNSCALE: u16 = 1;
-    const BLUR: u16 = 2;
+#[test]
+fn in_place_nodes() {
+    let mut graph = Graph::new();
+    let picture = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let blur = graph.add_node(TaskId::Render(0, 1), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[picture]);
+    let overlay = graph.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(100, 100), AllocKind::InPlace(picture), &[picture, blur]);
+    let other = graph.add_node(TaskId::Render(0, 3), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[blur]);
+    let root = graph.add_node(TaskId::Render(0, 4), TargetKind::Color, size2(400, 400), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[overlay, other]);
+    graph.add_root(root);
 
-    let pic1 = graph.add_node(TaskId::Render(PICTURE, 1), TargetKind::Color, size2(400, 200), AllocKind::Dynamic, &[]);
-    let ds1 = graph.add_node(TaskId::Render(DOWNSCALE, 0), TargetKind::Color, size2(200, 100), AllocKind::Dynamic, &[pic1]);
-    let ds2 = graph.add_node(TaskId::Render(DOWNSCALE, 1), TargetKind::Color, size2(100, 50), AllocKind::Dynamic, &[ds1]);
-    let vblur1 = graph.add_node(TaskId::Render(BLUR, 0), TargetKind::Color, size2(400, 300), AllocKind::Dynamic, &[ds2]);
-    let hblur1 = graph.add_node(TaskId::Render(BLUR, 1), TargetKind::Color, size2(500, 300), AllocKind::Dynamic, &[vblur1]);
+    for &targets in &[TargetOptions::Direct, TargetOptions::PingPong] {
+        for &allocations in &[AllocationStrategy::PassByPass, AllocationStrategy::Planned] {
+            let options = BuilderOptions { targets, allocations, .. BuilderOptions::default() };
+            let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+            let built = graph.clone().build(&options, &mut allocator).unwrap();
+
+            assert_eq!(built.in_place_owner(overlay), picture);
+            assert_eq!(built.allocated_rectangle(overlay), built.allocated_rectangle(picture));
+
+            // The in-place node renders into the texture of the picture, without
+            // overwriting the other node of its pass.
+            let picture_texture = built.passes()[0].dynamic_targets[TargetKind::Color as usize].destination;
+            let pass = &built.passes()[2];
+            let in_place_target = pass.dynamic_targets.iter()
+                .chain(pass.fixed_targets.iter())
+                .find(|target| target.tasks.iter().any(|task| task.node_id == overlay))
+                .unwrap();
+            assert_eq!(in_place_target.destination, picture_texture);
+            if pass.dynamic_targets[TargetKind::Color as usize].destination == picture_texture {
+                assert!(!built.allocated_rectangle(other).intersects(built.allocated_rectangle(picture)));
+            }
+        }
+    }
 
-    let vblur2 = graph.add_node(TaskId::Render(BLUR, 2), TargetKind::Color, size2(400, 350), AllocKind::Fixed(TextureId(1337), point2(0, 0)), &[ds2]);
-    let hblur2 = graph.add_node(TaskId::Render(BLUR, 3), TargetKind::Color, size2(550, 350), AllocKind::Dynamic, &[vblur2]);
+    // The picture can't be read once it is overwritten.
+    let mut conflict = graph.clone();
+    conflict.add_dependency(root, picture);
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    assert_eq!(
+        conflict.build(&BuilderOptions::default(), &mut allocator).err(),
+        Some(BuildError::InPlaceConflict { node: overlay, reader: root }),
+    );
 
-    let vblur3 = graph.add_node(TaskId::Render(BLUR, 4), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[ds1]);
-    let hblur3 = graph.add_node(TaskId::Render(BLUR, 5), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[vblur3]);
+    // In-place nodes must have the same size as the node they render into.
+    let mut invalid = graph.clone();
+    invalid.nodes[overlay.index()].size = size2(50, 50);
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    assert_eq!(
+        invalid.build(&BuilderOptions::default(), &mut allocator).err(),
+        Some(BuildError::InvalidInPlaceNode(overlay)),
+    );
 
-    let ds3 = graph.add_node(TaskId::Render(DOWNSCALE, 2), TargetKind::Color, size2(100, 50), AllocKind::Dynamic, &[ds2]);
-    let vblur4 = graph.add_node(TaskId::Render(BLUR, 3), TargetKind::Color, size2(500, 400), AllocKind::Dynamic, &[ds3]);
-    let hblur4 = graph.add_node(TaskId::Render(BLUR, 4), TargetKind::Color, size2(600, 400), AllocKind::Dynamic, &[vblur4]);
+    // Or the same allocation options, which define the rectangle they render into.
+    let mut invalid = graph.clone();
+    invalid.set_allocation_options(overlay, AllocationOptions { padding: 2, ..AllocationOptions::default() });
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    assert_eq!(
+        invalid.build(&BuilderOptions::default(), &mut allocator).err(),
+        Some(BuildError::InvalidInPlaceNode(overlay)),
+    );
 
-    let root = graph.add_node(
-        TaskId::Render(PICTURE, 0),
-        TargetKind::Color,
-        size2(1000, 1000),
-        AllocKind::Fixed(TextureId(123), point2(0, 0)),
-        &[pic1, hblur1, hblur2, hblur3, hblur4]
+    // The node they render into must be another node.
+    let mut invalid = graph.clone();
+    invalid.nodes[overlay.index()].alloc_kind = AllocKind::InPlace(overlay);
+    invalid.add_dependency(overlay, overlay);
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    assert_eq!(
+        invalid.build(&BuilderOptions::default(), &mut allocator).err(),
+        Some(BuildError::InvalidInPlaceNode(overlay)),
     );
+
+    // The node they render into must exist and be a dependency.
+    let mut invalid = graph.clone();
+    invalid.nodes[overlay.index()].alloc_kind = AllocKind::InPlace(NodeId(100));
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    assert_eq!(
+        invalid.build(&BuilderOptions::default(), &mut allocator).err(),
+        Some(BuildError::InvalidInPlaceNode(overlay)),
+    );
+
+    // Only one in-place node can render into a given node.
+    let mut two_writers = graph.clone();
+    let second = two_writers.add_node(TaskId::Render(0, 5), TargetKind::Color, size2(100, 100), AllocKind::InPlace(picture), &[picture]);
+    two_writers.add_dependency(root, second);
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    assert_eq!(
+        two_writers.build(&BuilderOptions::default(), &mut allocator).err(),
+        Some(BuildError::InPlaceWriters { node: second, other: overlay }),
+    );
+
+    // A node of the in-place node's pass can't read from the texture it renders into,
+    // here the texture of the picture, which also holds the node the sibling reads.
+    let mut graph = Graph::new();
+    let picture = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let other = graph.add_node(TaskId::Render(0, 1), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let overlay = graph.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(100, 100), AllocKind::InPlace(picture), &[picture]);
+    let sibling = graph.add_node(TaskId::Render(0, 3), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[other]);
+    let root = graph.add_node(TaskId::Render(0, 4), TargetKind::Color, size2(400, 400), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[overlay, sibling]);
     graph.add_root(root);
+    for &targets in &[TargetOptions::Direct, TargetOptions::PingPong] {
+        let options = BuilderOptions { targets, .. BuilderOptions::default() };
+        let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+        assert_eq!(
+            graph.clone().build(&options, &mut allocator).err(),
+            Some(BuildError::InPlaceConflict { node: overlay, reader: sibling }),
+        );
+    }
+}
 
-    for &with_deallocations in &[false, true] {
-        for &target_option in &[TargetOptions::Direct, TargetOptions::PingPong] {
-            build_and_print_graph(
-                &graph,
-                BuilderOptions {
-                    targets: target_option,
-                },
-                with_deallocations,
-            )
+#[test]
+fn side_effects() {
+    let mut graph = Graph::new();
+    let picture = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let root = graph.add_node(TaskId::Render(0, 1), TargetKind::Color, size2(400, 400), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[picture]);
+    let capture = graph.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[picture]);
+    let readback = graph.add_node(TaskId::Render(0, 3), TargetKind::Color, size2(100, 100), AllocKind::Fixed(TextureId(1), point2(0, 0)), &[capture]);
+    graph.add_root(root);
+
+    let is_scheduled = |built: &BuiltGraph, node: NodeId| built.passes().iter().any(|pass| {
+        pass.dynamic_targets.iter()
+            .chain(pass.fixed_targets.iter())
+            .any(|target| target.tasks.iter().any(|task| task.node_id == node))
+    });
+
+    // Nodes that the roots don't depend on are culled.
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    let built = graph.clone().build(&BuilderOptions::default(), &mut allocator).unwrap();
+    assert!(!is_scheduled(&built, readback));
+    assert!(!is_scheduled(&built, capture));
+
+    let mut with_side_effect = graph.clone();
+    with_side_effect.add_side_effect(readback);
+    let mut guillotine = GuillotineAllocator::new(size2(1024, 1024));
+    let mut allocator = DbgTextureAllocator::new(&mut guillotine);
+    let built = with_side_effect.build(&BuilderOptions::default(), &mut allocator).unwrap();
+    assert!(is_scheduled(&built, readback));
+    assert!(is_scheduled(&built, capture));
+    // All dynamic allocations are freed, unlike when the capture is a root.
+    assert!(allocator.allocations.is_empty());
+
+    let mut with_roots = graph.clone();
+    with_roots.add_root(readback);
+    with_roots.add_root(capture);
+    let mut guillotine = GuillotineAllocator::new(size2(1024, 1024));
+    let mut allocator = DbgTextureAllocator::new(&mut guillotine);
+    with_roots.build(&BuilderOptions::default(), &mut allocator).unwrap();
+    assert_eq!(allocator.allocations.len(), 1);
+}
+
+#[test]
+fn texture_sizes() {
+    let mut graph = Graph::new();
+    let n0 = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(1000, 100), AllocKind::Dynamic, &[]);
+    let n1 = graph.add_node(TaskId::Render(0, 1), TargetKind::Alpha, size2(100, 100), AllocKind::Dynamic, &[n0]);
+    let root = graph.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(800, 600), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[n1]);
+    graph.add_root(root);
+
+    // Textures grow to fit the allocations.
+    let mut guillotine = GuillotineAllocator::new(size2(512, 512));
+    let mut allocator = DbgTextureAllocator::new(&mut guillotine);
+    let built = graph.clone().build(&BuilderOptions::default(), &mut allocator).unwrap();
+    assert_eq!(built.texture_size(TextureId::dynamic(0)), Some(size2(1024, 1024)));
+    assert_eq!(built.texture_size(TextureId::dynamic(1)), Some(size2(512, 512)));
+    assert_eq!(built.texture_size(TextureId::dynamic(2)), None);
+    assert_eq!(built.texture_size(TextureId(0)), None);
+
+    // Planned textures are as large as the extents of their allocations.
+    let options = BuilderOptions { allocations: AllocationStrategy::Planned, .. BuilderOptions::default() };
+    let mut allocator = GuillotineAllocator::new(size2(512, 512));
+    let built = graph.build(&options, &mut allocator).unwrap();
+    assert_eq!(built.texture_size(TextureId::dynamic(0)), Some(size2(1000, 100)));
+    assert_eq!(built.texture_size(TextureId::dynamic(1)), Some(size2(100, 100)));
+
+    // Visualizations don't need the allocator.
+    crate::dump_svg(&mut Vec::new(), &built);
+}
+
+#[test]
+fn allocation_records() {
+    let mut graph = Graph::new();
+    let n0 = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let n1 = graph.add_node(TaskId::Render(0, 1), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n0]);
+    let n2 = graph.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n1]);
+    let dynamic_root = graph.add_node(TaskId::Render(0, 3), TargetKind::Color, size2(50, 50), AllocKind::Dynamic, &[n0]);
+    let root = graph.add_node(TaskId::Render(0, 4), TargetKind::Color, size2(800, 600), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[n2]);
+    graph.add_root(root);
+    graph.add_root(dynamic_root);
+
+    for &allocations in &[AllocationStrategy::PassByPass, AllocationStrategy::Planned] {
+        let options = BuilderOptions { allocations, .. BuilderOptions::default() };
+        let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+        let built = graph.clone().build(&options, &mut allocator).unwrap();
+
+        let record = |node| *built.allocations().iter().find(|record| record.output == NodeOutput::main(node)).unwrap();
+        assert_eq!(built.allocations().len(), 4);
+        // Allocations can be reused from the pass after their last read.
+        assert_eq!((record(n0).allocated, record(n0).deallocated), (0, Some(4)));
+        assert_eq!((record(n1).allocated, record(n1).deallocated), (1, Some(3)));
+        assert_eq!((record(n2).allocated, record(n2).deallocated), (2, Some(4)));
+        // Roots are kept until the end.
+        assert_eq!((record(dynamic_root).allocated, record(dynamic_root).deallocated), (3, None));
+        for record in built.allocations() {
+            assert!(record.texture.is_dynamic());
+        }
+
+        let mut output = Vec::new();
+        crate::dump_atlas_timeline(&mut output, &built);
+        let svg = String::from_utf8(output).unwrap();
+        assert!(svg.contains("Pass 3"));
+        assert!(svg.contains("stroke-dasharray"));
+    }
+}
+
+#[test]
+fn ping_pong_copy_without_target() {
+    // c3 reads c0 from the color texture it renders into. The copy goes in pass 2, which
+    // has no color target until the copy is added.
+    let mut graph = Graph::new();
+    let c0 = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let c1 = graph.add_node(TaskId::Render(0, 1), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[c0]);
+    let a2 = graph.add_node(TaskId::Render(0, 2), TargetKind::Alpha, size2(100, 100), AllocKind::Dynamic, &[c1]);
+    let c3 = graph.add_node(TaskId::Render(0, 3), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[a2, c0]);
+    let root = graph.add_node(TaskId::Render(0, 4), TargetKind::Color, size2(400, 400), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[c3]);
+    graph.add_root(root);
+
+    let options = BuilderOptions { targets: TargetOptions::PingPong, .. BuilderOptions::default() };
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    let built = graph.build(&options, &mut allocator).unwrap();
+
+    assert_eq!(built.copy_nodes().len(), 1);
+    let copy_target = &built.passes()[2].dynamic_targets[TargetKind::Color as usize];
+    assert_eq!(copy_target.tasks()[0].node_id, built.copy_nodes()[0]);
+    assert_ne!(copy_target.destination(), built.passes()[3].dynamic_targets[TargetKind::Color as usize].destination());
+
+    // a2 reads c1 from the other color texture in the same pass.
+    for target in built.passes()[2].dynamic_targets.iter().chain(built.passes()[2].fixed_targets.iter()) {
+        for task in target.tasks() {
+            for input in built.node_inputs(task.node_id) {
+                let input_target = built.passes().iter()
+                    .flat_map(|pass| pass.dynamic_targets.iter().chain(pass.fixed_targets.iter()))
+                    .find(|target| target.tasks().iter().any(|task| NodeOutput::new(task.node_id, task.output) == input))
+                    .unwrap();
+                assert_ne!(input_target.destination(), copy_target.destination());
+            }
         }
     }
 }
@@ -0,0 +1,269 @@
+use std::io::Write;
+use crate::BuiltGraph;
+use crate::svg::{Layout, SvgOptions, write_graph};
+
+/// Write a self-contained HTML page with an interactive visualization of a built graph.
+///
+/// The page shows the same layout as `dump_svg` and doesn't load anything from the
+/// network. Hovering a node highlights its dependencies and the nodes that depend on it,
+/// clicking it shows its allocated rectangles in a larger preview of its texture, and
+/// passes can be hidden individually.
+pub fn dump_html<T: std::fmt::Debug, U>(
+    output: &mut dyn Write,
+    graph: &BuiltGraph<T, U>,
+) {
+    let options = SvgOptions::default();
+    let layout = Layout::new(graph, &options);
+
+    write!(output, "{}", HTML_HEADER).unwrap();
+
+    write!(output,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" version="1.1" viewBox="0 0 {} {}" width="{}" height="{}">"#,
+        layout.size.width,
+        layout.size.height,
+        layout.size.width * 2.0,
+        layout.size.height * 2.0,
+    ).unwrap();
+    write_graph(output, graph, &layout, &options, true);
+    writeln!(output, "</svg>").unwrap();
+
+    writeln!(output, "</div>\n<script>").unwrap();
+    write_graph_data(output, graph, &layout);
+    write!(output, "{}", HTML_FOOTER).unwrap();
+}
+
+/// Write the nodes and targets as a javascript object used by the page's script.
+fn write_graph_data<T: std::fmt::Debug, U>(
+    output: &mut dyn Write,
+    graph: &BuiltGraph<T, U>,
+    layout: &Layout,
+) {
+    writeln!(output, "const graph = {{").unwrap();
+    writeln!(output, "  passes: {},", graph.passes().len()).unwrap();
+
+    writeln!(output, "  nodes: [").unwrap();
+    for id in graph.node_ids() {
+        let inputs: Vec<String> = graph.node_inputs(id).map(|input| input.node.index().to_string()).collect();
+        writeln!(output,
+            "    {{ name: {}, task: {}, size: [{}, {}], inputs: [{}] }},",
+            json_string(&graph.node_name(id)),
+            json_string(&format!("{:?}", graph[id].task_id)),
+            graph[id].size.width,
+            graph[id].size.height,
+            inputs.join(", "),
+        ).unwrap();
+    }
+    writeln!(output, "  ],").unwrap();
+
+    writeln!(output, "  targets: [").unwrap();
+    for target in &layout.targets {
+        let allocations: Vec<String> = target.allocations.iter().map(|(alloc_output, rect)| {
+            format!(
+                "[{}, {}, {}, {}, {}, {}]",
+                alloc_output.node.index(),
+                alloc_output.index,
+                rect.min.x, rect.min.y,
+                rect.max.x, rect.max.y,
+            )
+        }).collect();
+        writeln!(output,
+            "    {{ pass: {}, texture: {}, width: {}, height: {}, allocations: [{}] }},",
+            target.pass,
+            json_string(&format!("{:?}", target.destination.unwrap())),
+            target.texture_size.width,
+            target.texture_size.height,
+            allocations.join(", "),
+        ).unwrap();
+    }
+    writeln!(output, "  ],").unwrap();
+    writeln!(output, "}};").unwrap();
+}
+
+/// A double-quoted string literal, also safe to embed in a script element.
+pub(crate) fn json_string(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '<' => escaped.push_str("\\u003c"),
+            '>' => escaped.push_str("\\u003e"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            _ => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+
+    escaped
+}
+
+const HTML_HEADER: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Render graph</title>
+<style>
+body { margin: 0; display: flex; height: 100vh; background: rgb(50,50,50); color: rgb(220,220,220); font-family: sans-serif; font-size: 13px; }
+#panel { width: 280px; flex-shrink: 0; padding: 10px; overflow: auto; background: rgb(35,35,35); }
+#panel h3 { margin: 10px 0 5px 0; }
+#panel label { display: block; }
+#view { flex-grow: 1; overflow: auto; }
+text { pointer-events: none; }
+rect.node { cursor: pointer; }
+rect.node.hovered { fill: rgb(250,250,250) !important; }
+rect.node.dependency, path.dependency { fill: rgb(120,200,120) !important; stroke: rgb(120,200,120) !important; }
+rect.node.dependent, path.dependent { fill: rgb(230,170,80) !important; stroke: rgb(230,170,80) !important; }
+path.dependency, path.dependent { fill: none !important; }
+rect.selected { fill: rgb(250,100,50) !important; fill-opacity: 1 !important; }
+</style>
+</head>
+<body>
+<div id="panel">
+<h3>Passes</h3>
+<div id="passes"></div>
+<h3 id="selection">Click a node to show its allocations.</h3>
+<div id="previews"></div>
+</div>
+<div id="view">
+"##;
+
+const HTML_FOOTER: &str = r##"
+const svg = document.querySelector("#view svg");
+const SVG_NS = "http://www.w3.org/2000/svg";
+
+const dependents = graph.nodes.map(() => []);
+graph.nodes.forEach((node, id) => node.inputs.forEach(input => dependents[input].push(id)));
+
+function select(selector) {
+    return Array.from(svg.querySelectorAll(selector));
+}
+
+function highlight(id, enabled) {
+    select(`rect.node[data-node="${id}"]`).forEach(e => e.classList.toggle("hovered", enabled));
+    for (const input of graph.nodes[id].inputs) {
+        select(`rect.node[data-node="${input}"], path.link[data-from="${input}"][data-to="${id}"]`)
+            .forEach(e => e.classList.toggle("dependency", enabled));
+    }
+    for (const dependent of dependents[id]) {
+        select(`rect.node[data-node="${dependent}"], path.link[data-from="${id}"][data-to="${dependent}"]`)
+            .forEach(e => e.classList.toggle("dependent", enabled));
+    }
+}
+
+function showAllocations(id) {
+    select(".selected").forEach(e => e.classList.remove("selected"));
+    select(`rect.allocation[data-node="${id}"]`).forEach(e => e.classList.add("selected"));
+
+    const node = graph.nodes[id];
+    document.getElementById("selection").textContent = `${node.name} - ${node.task} - ${node.size[0]}x${node.size[1]}`;
+    const previews = document.getElementById("previews");
+    previews.innerHTML = "";
+
+    for (const target of graph.targets) {
+        const allocations = target.allocations.filter(a => a[0] === id);
+        if (allocations.length === 0) {
+            continue;
+        }
+
+        const title = document.createElement("div");
+        title.textContent = `Pass ${target.pass}, ${target.texture} (${target.width}x${target.height})`;
+        previews.appendChild(title);
+        for (const a of allocations) {
+            const rect = document.createElement("div");
+            rect.textContent = `Output ${a[1]}: [${a[2]}, ${a[3]}] - [${a[4]}, ${a[5]}]`;
+            previews.appendChild(rect);
+        }
+
+        const width = 260;
+        const preview = document.createElementNS(SVG_NS, "svg");
+        preview.setAttribute("viewBox", `0 0 ${target.width} ${target.height}`);
+        preview.setAttribute("width", width);
+        preview.setAttribute("height", width * target.height / Math.max(target.width, 1));
+        const background = document.createElementNS(SVG_NS, "rect");
+        background.setAttribute("width", target.width);
+        background.setAttribute("height", target.height);
+        background.setAttribute("style", "fill:black;fill-opacity:0.5");
+        preview.appendChild(background);
+        for (const a of target.allocations) {
+            const rect = document.createElementNS(SVG_NS, "rect");
+            rect.setAttribute("x", a[2]);
+            rect.setAttribute("y", a[3]);
+            rect.setAttribute("width", a[4] - a[2]);
+            rect.setAttribute("height", a[5] - a[3]);
+            const color = a[0] === id ? "rgb(250,100,50)" : "rgb(50,70,180)";
+            rect.setAttribute("style", `fill:${color};stroke:black;stroke-width:1px;vector-effect:non-scaling-stroke`);
+            const tooltip = document.createElementNS(SVG_NS, "title");
+            tooltip.textContent = graph.nodes[a[0]].name;
+            rect.appendChild(tooltip);
+            preview.appendChild(rect);
+        }
+        previews.appendChild(preview);
+    }
+}
+
+for (const e of select("rect.node")) {
+    const id = Number(e.dataset.node);
+    e.addEventListener("mouseenter", () => highlight(id, true));
+    e.addEventListener("mouseleave", () => highlight(id, false));
+    e.addEventListener("click", () => showAllocations(id));
+}
+
+const hiddenPasses = new Set();
+function updatePasses() {
+    for (const e of select("[data-pass]")) {
+        const hidden = e.dataset.pass.split(" ").some(pass => hiddenPasses.has(Number(pass)));
+        e.style.display = hidden ? "none" : "";
+    }
+}
+
+const passes = document.getElementById("passes");
+for (let pass = 0; pass < graph.passes; pass++) {
+    const label = document.createElement("label");
+    const checkbox = document.createElement("input");
+    checkbox.type = "checkbox";
+    checkbox.checked = true;
+    checkbox.addEventListener("change", () => {
+        if (checkbox.checked) {
+            hiddenPasses.delete(pass);
+        } else {
+            hiddenPasses.add(pass);
+        }
+        updatePasses();
+    });
+    label.appendChild(checkbox);
+    label.appendChild(document.createTextNode(` Pass ${pass}`));
+    passes.appendChild(label);
+}
+</script>
+</body>
+</html>
+"##;
+
+#[test]
+fn html_output() {
+    use crate::*;
+
+    let mut graph = Graph::new();
+    let n0 = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let n1 = graph.add_node(TaskId::Render(0, 1), TargetKind::Alpha, size2(100, 100), AllocKind::Dynamic, &[n0]);
+    let root = graph.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(400, 400), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[n0, n1]);
+    graph.set_label(root, "</script>".to_string());
+    graph.add_root(root);
+
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    let built = graph.build(&BuilderOptions::default(), &mut allocator).unwrap();
+
+    let mut output = Vec::new();
+    dump_html(&mut output, &built);
+    let html = String::from_utf8(output).unwrap();
+
+    for id in built.node_ids() {
+        assert!(html.contains(&format!(r#"class="node" data-node="{}""#, id.index())));
+    }
+    assert!(html.contains(r#"class="link" data-from="0" data-to="1" data-pass="0 1""#));
+    assert!(html.contains("passes: 3,"));
+    // Labels can't break out of the script.
+    assert_eq!(html.matches("</script>").count(), 1);
+}
@@ -3,17 +3,28 @@ pub extern crate guillotiere;
 #[cfg(feature = "serialization")]
 #[macro_use]
 pub extern crate serde;
-#[macro_use]
 pub extern crate smallvec;
+#[cfg(feature = "rayon")]
+extern crate rayon;
 
 mod graph;
 mod allocator;
+mod planner;
+mod subgraph;
+mod dot;
+mod trace;
+mod display;
 pub mod parallel;
 pub mod svg;
+pub mod html;
 
 pub use graph::*;
 pub use allocator::*;
-pub use svg::dump_svg;
+pub use planner::MemoryStats;
+pub use subgraph::SubGraph;
+pub use display::{VerboseDisplay, CompactDisplay};
+pub use svg::{dump_svg, dump_svg_with_options, dump_atlas_timeline, SvgOptions, SvgTheme, NodeColors};
+pub use html::dump_html;
 
 type FloatRectangle = euclid::Box2D<f32>;
 type FloatPoint = euclid::Point2D<f32>;
@@ -3,117 +3,504 @@ use std::sync::{
     atomic::{AtomicUsize, Ordering},
     mpsc::{channel, Sender, Receiver},
 };
-use euclid::{size2};
-use smallvec::SmallVec;
 use crate::graph::*;
 
-enum Edit {
-    AddNode(Node, NodeId),
-    AddDependency(NodeId, NodeId),
-    AddRoot(NodeId),
+/// Number of node ids reserved at once by a remote builder.
+const CHUNK_SIZE: usize = 256;
+
+/// The nodes and edits of a single remote builder.
+///
+/// Node ids handed out by remote builders are provisional: they are taken from chunks
+/// of ids reserved with a single atomic operation, and are remapped to contiguous ids
+/// when the fragments are merged.
+struct Fragment<T, U> {
+    /// The join the fragment is part of. Fragments sent after their join are ignored.
+    generation: usize,
+    remote_index: usize,
+    /// Start of each chunk of ids reserved by the remote builder, in order.
+    chunks: Vec<usize>,
+    /// The nodes of this fragment. Dependencies use provisional ids.
+    graph: Graph<T, U>,
+    /// Edits targeting nodes of other fragments.
+    dependencies: Vec<(NodeId, NodeId)>,
+    alloc_options: Vec<(NodeId, AllocationOptions)>,
+    labels: Vec<(NodeId, String)>,
+    output_dependencies: Vec<(NodeId, NodeOutput)>,
+    user_data: Vec<(NodeId, U)>,
+}
+
+impl<T, U> Fragment<T, U> {
+    fn new(generation: usize, remote_index: usize) -> Self {
+        Fragment {
+            generation,
+            remote_index,
+            chunks: Vec::new(),
+            graph: Graph::default(),
+            dependencies: Vec::new(),
+            alloc_options: Vec::new(),
+            labels: Vec::new(),
+            output_dependencies: Vec::new(),
+            user_data: Vec::new(),
+        }
+    }
+
+    fn local_index(&self, id: NodeId) -> Option<usize> {
+        let chunk_start = id.index() - id.index() % CHUNK_SIZE;
+        let nth_chunk = self.chunks.iter().position(|&start| start == chunk_start)?;
+        let idx = nth_chunk * CHUNK_SIZE + id.index() % CHUNK_SIZE;
+        if idx < self.graph.nodes.len() {
+            Some(idx)
+        } else {
+            None
+        }
+    }
 }
 
+/// Builds part of a graph on a separate thread.
+///
+/// The nodes are added to a local fragment without synchronization and sent to the
+/// `ParallelGraphBuilder` when the remote builder is dropped, so remote builders must be
+/// dropped before the `join` their nodes are part of. A remote builder that is still
+/// alive makes the `join` fail with `JoinError::OutstandingRemotes`, and its fragment is
+/// discarded when it is eventually dropped.
+pub struct RemoteGraphBuilder<T = TaskId, U = ()> {
+    shared: Shared,
+    sender: Sender<Fragment<T, U>>,
+    fragment: Fragment<T, U>,
+}
+
+/// The counters shared by a parallel builder and its remote builders.
 #[derive(Clone)]
-pub struct RemoteGraphBuilder {
-    next_node_id: Arc<AtomicUsize>,
-    sender: Sender<Edit>,
+struct Shared {
+    next_chunk: Arc<AtomicUsize>,
+    next_remote: Arc<AtomicUsize>,
+    generation: Arc<AtomicUsize>,
 }
 
-impl GraphBuilder for RemoteGraphBuilder {
+impl<T, U> RemoteGraphBuilder<T, U> {
+    fn new(shared: Shared, sender: Sender<Fragment<T, U>>) -> Self {
+        let generation = shared.generation.load(Ordering::Relaxed);
+        let remote_index = shared.next_remote.fetch_add(1, Ordering::Relaxed);
+        RemoteGraphBuilder {
+            shared,
+            sender,
+            fragment: Fragment::new(generation, remote_index),
+        }
+    }
+}
+
+impl<T, U> RemoteGraphBuilder<T, U> {
+    /// Create a builder for another thread, like `ParallelGraphBuilder::new_remote`.
+    ///
+    /// The new builder starts with an empty fragment: its nodes are placed in the joined
+    /// graph after the nodes of the remote builders created before it. The ids returned
+    /// by either builder can be used with both of them.
+    pub fn new_remote(&self) -> Self {
+        RemoteGraphBuilder::new(self.shared.clone(), self.sender.clone())
+    }
+}
+
+impl<T, U> Drop for RemoteGraphBuilder<T, U> {
+    fn drop(&mut self) {
+        let fragment = std::mem::replace(&mut self.fragment, Fragment::new(0, 0));
+        // The parallel builder may have been dropped already, in which case there is
+        // nobody to send the fragment to.
+        let _ = self.sender.send(fragment);
+    }
+}
+
+impl<T, U> GraphBuilder<T, U> for RemoteGraphBuilder<T, U> {
     fn add_node(
         &mut self,
-        task_id: TaskId,
+        task_id: T,
         target_kind: TargetKind,
         size: Size,
         alloc_kind: AllocKind,
         deps: &[NodeId]
     ) -> NodeId {
-        let id = node_id(self.next_node_id.fetch_add(1, Ordering::SeqCst));
-        self.sender.send(Edit::AddNode(
-            Node {
-                task_id,
-                size,
-                alloc_kind,
-                dependencies: SmallVec::from_slice(deps),
-                target_kind,
-            },
-            id,
-        )).unwrap();
+        let local = self.fragment.graph.add_node(task_id, target_kind, size, alloc_kind, deps).index();
+        if local / CHUNK_SIZE == self.fragment.chunks.len() {
+            // Reserve a new chunk of ids.
+            let chunk = self.shared.next_chunk.fetch_add(1, Ordering::Relaxed);
+            self.fragment.chunks.push(chunk * CHUNK_SIZE);
+        }
 
-        id
+        node_id(self.fragment.chunks[local / CHUNK_SIZE] + local % CHUNK_SIZE)
     }
 
     fn add_dependency(&mut self, node: NodeId, dep: NodeId) {
-        self.sender.send(Edit::AddDependency(node, dep)).unwrap();
+        match self.fragment.local_index(node) {
+            Some(idx) => self.fragment.graph.add_dependency(node_id(idx), dep),
+            None => self.fragment.dependencies.push((node, dep)),
+        }
     }
 
     fn add_root(&mut self, node: NodeId) {
-        self.sender.send(Edit::AddRoot(node)).unwrap();
+        self.fragment.graph.add_root(node);
+    }
+
+    fn add_side_effect(&mut self, node: NodeId) {
+        self.fragment.graph.add_side_effect(node);
+    }
+
+    fn set_allocation_options(&mut self, node: NodeId, options: AllocationOptions) {
+        match self.fragment.local_index(node) {
+            Some(idx) => self.fragment.graph.set_allocation_options(node_id(idx), options),
+            None => self.fragment.alloc_options.push((node, options)),
+        }
+    }
+
+    fn set_label(&mut self, node: NodeId, label: String) {
+        match self.fragment.local_index(node) {
+            Some(idx) => self.fragment.graph.set_label(node_id(idx), label),
+            None => self.fragment.labels.push((node, label)),
+        }
+    }
+
+    /// Only nodes created by this remote builder can be given additional outputs.
+    fn add_output(&mut self, node: NodeId, target_kind: TargetKind, size: Size) -> NodeOutput {
+        let idx = self.fragment.local_index(node).expect("Outputs can only be added to local nodes");
+        let output = self.fragment.graph.add_output(node_id(idx), target_kind, size);
+
+        NodeOutput::new(node, output.index)
+    }
+
+    fn add_output_dependency(&mut self, node: NodeId, output: NodeOutput) {
+        if output.index == 0 {
+            return self.add_dependency(node, output.node);
+        }
+        match self.fragment.local_index(node) {
+            Some(idx) => self.fragment.graph.nodes[idx].output_dependencies.push(output),
+            None => self.fragment.output_dependencies.push((node, output)),
+        }
+    }
+
+    fn set_user_data(&mut self, node: NodeId, data: U) {
+        match self.fragment.local_index(node) {
+            Some(idx) => self.fragment.graph.set_user_data(node_id(idx), data),
+            None => self.fragment.user_data.push((node, data)),
+        }
     }
 }
 
-pub struct ParallelGraphBuilder {
-    next_node_id: Arc<AtomicUsize>,
-    sender: Sender<Edit>,
-    receiver: Receiver<Edit>,
+/// Maps the provisional node ids returned by remote builders to the ids of the
+/// joined graph.
+#[derive(Clone, Debug, Default)]
+pub struct NodeIdMap {
+    /// For each chunk of provisional ids, the id of its first node in the joined graph
+    /// and the number of nodes in the chunk.
+    chunks: Vec<Option<(usize, usize)>>,
 }
 
-impl ParallelGraphBuilder {
-    pub fn new() -> Self {
+impl NodeIdMap {
+    pub fn get(&self, id: NodeId) -> Option<NodeId> {
+        let (start, count) = (*self.chunks.get(id.index() / CHUNK_SIZE)?)?;
+        let offset = id.index() % CHUNK_SIZE;
+        if offset < count {
+            Some(node_id(start + offset))
+        } else {
+            None
+        }
+    }
+}
+
+pub struct ParallelGraphBuilder<T = TaskId, U = ()> {
+    shared: Shared,
+    sender: Sender<Fragment<T, U>>,
+    receiver: Receiver<Fragment<T, U>>,
+}
+
+impl<T, U> Default for ParallelGraphBuilder<T, U> {
+    fn default() -> Self {
         let (sender, receiver) = channel();
         ParallelGraphBuilder {
-            next_node_id: Arc::new(AtomicUsize::new(0)),
+            shared: Shared {
+                next_chunk: Arc::new(AtomicUsize::new(0)),
+                next_remote: Arc::new(AtomicUsize::new(0)),
+                generation: Arc::new(AtomicUsize::new(0)),
+            },
             sender,
             receiver,
         }
     }
+}
 
-    pub fn new_remote(&self) -> RemoteGraphBuilder {
-        RemoteGraphBuilder {
-            next_node_id: self.next_node_id.clone(),
-            sender: self.sender.clone(),
-        }
-    }
-
-    pub fn join(&self) -> Graph {
-        let capacity = self.next_node_id.load(Ordering::SeqCst) - 1;
-        self.next_node_id.store(0, Ordering::SeqCst);
-
-        let mut graph = Graph::with_capacity(capacity, 0);
-
-        loop {
-            match self.receiver.try_recv() {
-                Ok(Edit::AddNode(node, id)) => {
-                    // Push a dummy nodes if the index doesn't exist yet.
-                    while id.index() > graph.nodes.len() {
-                        graph.nodes.push(Node {
-                            task_id: TaskId::Render(std::u16::MAX, std::u32::MAX),
-                            size: size2(0, 0),
-                            alloc_kind: AllocKind::Dynamic,
-                            dependencies: SmallVec::new(),
-                            target_kind: TargetKind::Color,
-                        });
-                    }
-
-                    if id.index() == graph.nodes.len() {
-                        // Common case.
-                        graph.nodes.push(node);
-                    } else {
-                        graph.nodes[id.index()] = node;
-                    }
+impl<T> ParallelGraphBuilder<T> {
+    /// Builders of graphs with user data can be created with
+    /// `ParallelGraphBuilder::default`.
+    pub fn new() -> Self {
+        ParallelGraphBuilder::default()
+    }
+}
+
+impl<T, U> ParallelGraphBuilder<T, U> {
+    /// Create a builder for another thread.
+    ///
+    /// The nodes of each remote builder are placed in the joined graph in the order the
+    /// remote builders were created.
+    pub fn new_remote(&self) -> RemoteGraphBuilder<T, U> {
+        RemoteGraphBuilder::new(self.shared.clone(), self.sender.clone())
+    }
+
+    /// Merge the fragments of all remote builders into a graph, and return the mapping
+    /// from the node ids returned by the remote builders to the ids in the graph.
+    ///
+    /// The ids returned by `RemoteGraphBuilder::add_node` are provisional: they are only
+    /// valid for edits made through remote builders, and are not indices into the joined
+    /// graph. Code that used them to look up nodes in the graph returned by `join` must
+    /// now map them through `NodeIdMap::get` first:
+    ///
+    /// ```ignore
+    /// let (graph, ids) = builder.join()?;
+    /// let node = &graph[ids.get(provisional_id).unwrap()];
+    /// ```
+    ///
+    /// Must be called once all remote builders have been dropped, and not while new
+    /// remote builders are being created. If some of them are still alive,
+    /// `JoinError::OutstandingRemotes` is returned. The fragments are concatenated in the
+    /// order their remote builders were created and the edits targeting other fragments
+    /// are applied once all nodes are known, so the result doesn't depend on how the
+    /// threads were interleaved.
+    ///
+    /// All of the fragments are received before anything can fail, so if an error is
+    /// returned they are discarded and the builder can be used for the next graph.
+    pub fn join(&self) -> Result<(Graph<T, U>, NodeIdMap), JoinError> {
+        let generation = self.shared.generation.fetch_add(1, Ordering::Relaxed);
+        self.shared.next_chunk.store(0, Ordering::Relaxed);
+        let num_remotes = self.shared.next_remote.swap(0, Ordering::Relaxed);
+
+        let mut fragments = Vec::new();
+        while let Ok(fragment) = self.receiver.try_recv() {
+            // Remote builders that were still alive during a previous join, which
+            // reported them.
+            if fragment.generation == generation {
+                fragments.push(fragment);
+            }
+        }
+        if fragments.len() < num_remotes {
+            return Err(JoinError::OutstandingRemotes(num_remotes - fragments.len()));
+        }
+        fragments.sort_by_key(|fragment| fragment.remote_index);
+
+        let num_chunks = fragments.iter()
+            .flat_map(|fragment| fragment.chunks.iter())
+            .map(|&start| start / CHUNK_SIZE + 1)
+            .max()
+            .unwrap_or(0);
+        let mut id_map = NodeIdMap { chunks: vec![None; num_chunks] };
+        let mut num_nodes = 0;
+        for fragment in &fragments {
+            let count = fragment.graph.nodes.len();
+            for (nth, &start) in fragment.chunks.iter().enumerate() {
+                let chunk_count = (count - nth * CHUNK_SIZE).min(CHUNK_SIZE);
+                id_map.chunks[start / CHUNK_SIZE] = Some((num_nodes + nth * CHUNK_SIZE, chunk_count));
+            }
+            num_nodes += count;
+        }
+
+        let remap = |id: NodeId| id_map.get(id).ok_or(JoinError::UnknownNode(id));
+
+        let mut graph = Graph::with_capacity(num_nodes, 0);
+        for fragment in &mut fragments {
+            let mut user_data = std::mem::take(&mut fragment.graph.user_data).into_iter();
+            for mut node in fragment.graph.nodes.drain(..) {
+                for dep in &mut node.dependencies {
+                    *dep = remap(*dep)?;
                 }
-                Ok(Edit::AddDependency(node, dep)) => {
-                    graph.nodes[node.index()].dependencies.push(dep);
+                for output in &mut node.output_dependencies {
+                    output.node = remap(output.node)?;
                 }
-                Ok(Edit::AddRoot(id)) => {
-                    graph.roots.push(id);
+                if let AllocKind::InPlace(dep) = &mut node.alloc_kind {
+                    *dep = remap(*dep)?;
                 }
-                Err(..) => {
-                    break;
+                graph.nodes.push(node);
+                if let Some(data) = user_data.next().flatten() {
+                    graph.set_user_data(node_id(graph.nodes.len() - 1), data);
                 }
             }
         }
 
-        graph
+        for fragment in &mut fragments {
+            for &(node, dep) in &fragment.dependencies {
+                graph.nodes[remap(node)?.index()].dependencies.push(remap(dep)?);
+            }
+            for &(node, options) in &fragment.alloc_options {
+                graph.nodes[remap(node)?.index()].alloc_options = options;
+            }
+            for (node, label) in &fragment.labels {
+                graph.nodes[remap(*node)?.index()].label = Some(label.clone());
+            }
+            for &(node, output) in &fragment.output_dependencies {
+                let output = NodeOutput::new(remap(output.node)?, output.index);
+                graph.nodes[remap(node)?.index()].output_dependencies.push(output);
+            }
+            for &root in fragment.graph.roots() {
+                graph.roots.push(remap(root)?);
+            }
+            for &node in fragment.graph.side_effects() {
+                graph.side_effects.push(remap(node)?);
+            }
+            for (node, data) in fragment.user_data.drain(..) {
+                graph.set_user_data(remap(node)?, data);
+            }
+        }
+
+        Ok((graph, id_map))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum JoinError {
+    /// An edit references a node that was never created.
+    UnknownNode(NodeId),
+    /// Some remote builders were not dropped before the join, so their nodes are missing.
+    OutstandingRemotes(usize),
+}
+
+impl std::fmt::Display for JoinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            JoinError::UnknownNode(id) => write!(f, "an edit references {:?} which was never created", id),
+            JoinError::OutstandingRemotes(count) => write!(f, "{} remote builders were not dropped before the join", count),
+        }
     }
 }
+
+impl std::error::Error for JoinError {}
+
+#[test]
+fn join() {
+    use euclid::size2;
+
+    let builder = ParallelGraphBuilder::new();
+    assert_eq!(builder.join().unwrap().0.num_nodes(), 0);
+
+    let mut main = builder.new_remote();
+    let remotes: Vec<_> = (0..4).map(|_| builder.new_remote()).collect();
+
+    let root = main.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    main.add_root(root);
+    drop(main);
+
+    let threads: Vec<_> = remotes.into_iter().enumerate().map(|(t, mut remote)| {
+        std::thread::spawn(move || {
+            let t = t as u32;
+            let mut prev = remote.add_node(TaskId::Render(1, t), TargetKind::Alpha, size2(10, 10), AllocKind::Dynamic, &[]);
+            // Enough nodes to need several chunks of ids.
+            for i in 0..300 {
+                let node = remote.add_node(TaskId::Render(2, t * 1000 + i), TargetKind::Color, size2(10, 10), AllocKind::Dynamic, &[]);
+                remote.add_dependency(node, prev);
+                prev = node;
+            }
+            remote.add_dependency(root, prev);
+            remote.set_label(root, format!("root {}", t));
+        })
+    }).collect();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let (graph, ids) = builder.join().unwrap();
+    assert_eq!(graph.num_nodes(), 1205);
+    assert_eq!(ids.get(root), Some(NodeId(0)));
+    assert_eq!(graph.roots(), &[NodeId(0)]);
+    assert_eq!(graph.label(NodeId(0)), Some("root 3"));
+
+    // The nodes of each remote builder are contiguous and in the order the remote
+    // builders were created, regardless of the thread interleaving.
+    for t in 0..4u32 {
+        let first = 1 + t as usize * 301;
+        assert_eq!(graph[node_id(first)].task_id, TaskId::Render(1, t));
+        for i in 0..300 {
+            let id = node_id(first + 1 + i as usize);
+            assert_eq!(graph[id].task_id, TaskId::Render(2, t * 1000 + i));
+            assert_eq!(graph.node_dependencies(id), &[node_id(first + i as usize)]);
+        }
+        assert_eq!(graph.node_dependencies(NodeId(0))[t as usize], node_id(first + 300));
+    }
+
+    // Edits referencing nodes that were never created are reported.
+    let mut remote = builder.new_remote();
+    let n0 = remote.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    remote.add_dependency(n0, NodeId(10));
+    drop(remote);
+    assert_eq!(builder.join().err(), Some(JoinError::UnknownNode(NodeId(10))));
+}
+
+#[test]
+fn join_after_error() {
+    use euclid::size2;
+
+    let builder = ParallelGraphBuilder::new();
+
+    let mut a = builder.new_remote();
+    let mut b = builder.new_remote();
+    let n0 = a.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    a.add_dependency(n0, NodeId(1000));
+    b.add_node(TaskId::Render(0, 1), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    drop(a);
+    drop(b);
+    assert_eq!(builder.join().err(), Some(JoinError::UnknownNode(NodeId(1000))));
+
+    // Nothing from the failed join leaks into the next one.
+    let mut remote = builder.new_remote();
+    let n0 = remote.add_node(TaskId::Render(1, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    remote.add_root(n0);
+    drop(remote);
+    let (graph, ids) = builder.join().unwrap();
+    assert_eq!(graph.num_nodes(), 1);
+    assert_eq!(graph[ids.get(n0).unwrap()].task_id, TaskId::Render(1, 0));
+    assert_eq!(graph.roots(), &[NodeId(0)]);
+}
+
+#[test]
+fn remote_dropped_after_join() {
+    use euclid::size2;
+
+    let builder = ParallelGraphBuilder::new();
+
+    // Kept alive across the join, with enough nodes to reserve several chunks.
+    let mut late = builder.new_remote();
+    let mut remote = late.new_remote();
+    for i in 0..300 {
+        late.add_node(TaskId::Render(0, i), TargetKind::Color, size2(10, 10), AllocKind::Dynamic, &[]);
+    }
+    remote.add_node(TaskId::Render(0, 300), TargetKind::Color, size2(10, 10), AllocKind::Dynamic, &[]);
+    drop(remote);
+    assert_eq!(builder.join().err(), Some(JoinError::OutstandingRemotes(1)));
+
+    let mut remote = builder.new_remote();
+    let n0 = remote.add_node(TaskId::Render(1, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    remote.add_root(n0);
+    drop(remote);
+    drop(late);
+
+    // The fragment of the late remote builder belongs to the failed join.
+    let (graph, ids) = builder.join().unwrap();
+    assert_eq!(graph.num_nodes(), 1);
+    assert_eq!(graph[ids.get(n0).unwrap()].task_id, TaskId::Render(1, 0));
+}
+
+#[test]
+fn join_user_data() {
+    use euclid::size2;
+
+    let builder: ParallelGraphBuilder<TaskId, u32> = ParallelGraphBuilder::default();
+    let mut a = builder.new_remote();
+    let mut b = builder.new_remote();
+    let n0 = a.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let n1 = a.add_node(TaskId::Render(0, 1), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let n2 = b.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n0]);
+    a.set_user_data(n1, 1);
+    // Set by another remote builder than the one that created the node.
+    b.set_user_data(n0, 0);
+    b.set_user_data(n2, 2);
+    drop(a);
+    drop(b);
+
+    let (graph, ids) = builder.join().unwrap();
+    assert_eq!(graph.user_data(ids.get(n0).unwrap()), Some(&0));
+    assert_eq!(graph.user_data(ids.get(n1).unwrap()), Some(&1));
+    assert_eq!(graph.user_data(ids.get(n2).unwrap()), Some(&2));
+}
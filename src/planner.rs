@@ -0,0 +1,350 @@
+use crate::graph::*;
+use crate::graph::NodeRectangles;
+use crate::{Rectangle, Size, TextureId};
+use euclid::{point2, size2};
+
+/// The range of passes (inclusive) during which the allocation of a node must be
+/// preserved.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct Lifetime {
+    pub first: usize,
+    pub last: usize,
+}
+
+impl Lifetime {
+    #[cfg(test)]
+    pub fn overlaps(&self, other: &Lifetime) -> bool {
+        self.first <= other.last && other.first <= self.last
+    }
+}
+
+/// Compute the lifetime of each node that is assigned to a pass.
+///
+/// A node lives from the pass it is rendered in to the last pass that reads any of its
+/// outputs. Roots live until the end of the graph. Nodes that in-place nodes render into
+/// live at least as long as the latter.
+pub(crate) fn node_lifetimes<T, U>(graph: &Graph<T, U>, passes: &[Pass<T>]) -> Vec<Option<Lifetime>> {
+    let mut lifetimes = vec![None; graph.nodes.len()];
+
+    for (pass_index, pass) in passes.iter().enumerate() {
+        for target in pass.dynamic_targets.iter().chain(pass.fixed_targets.iter()) {
+            for task in &target.tasks {
+                lifetimes[task.node_id.index()] = Some(Lifetime {
+                    first: pass_index,
+                    last: pass_index,
+                });
+            }
+        }
+    }
+
+    for (pass_index, pass) in passes.iter().enumerate() {
+        for target in pass.dynamic_targets.iter().chain(pass.fixed_targets.iter()) {
+            for task in &target.tasks {
+                for input in graph.node_inputs(task.node_id) {
+                    if let Some(lifetime) = &mut lifetimes[input.node.index()] {
+                        lifetime.last = lifetime.last.max(pass_index);
+                    }
+                }
+            }
+        }
+    }
+
+    let last_pass = passes.len().max(1) - 1;
+    for root in &graph.roots {
+        if let Some(lifetime) = &mut lifetimes[root.index()] {
+            lifetime.last = last_pass;
+        }
+    }
+
+    // The allocation of a node lives as long as the in-place nodes rendering into it.
+    for id in graph.node_ids() {
+        if let (AllocKind::InPlace(..), Some(lifetime)) = (graph.nodes[id.index()].alloc_kind, lifetimes[id.index()]) {
+            let owner = &mut lifetimes[graph.in_place_owner(id).index()];
+            if let Some(owner) = owner {
+                owner.last = owner.last.max(lifetime.last);
+            }
+        }
+    }
+
+    lifetimes
+}
+
+/// Allocate the rectangles of all dynamic nodes by looking at the whole graph at once
+/// instead of allocating and deallocating pass by pass.
+///
+/// Each node is a rectangle over time (its lifetime). Nodes are packed per texture, largest
+/// first, on top of the nodes already placed with an overlapping lifetime, see `pack`. A few
+/// texture widths are tried and the one that yields the smallest texture area is kept.
+///
+/// The texture allocator is not involved: the planned textures are exactly as large as the
+/// extents of their allocations.
+pub(crate) fn plan_target_rects<T, U>(
+    graph: &Graph<T, U>,
+    passes: &[Pass<T>],
+    allocated_rectangles: &mut [NodeRectangles],
+    records: &mut Vec<AllocationRecord>,
+) {
+    let lifetimes = node_lifetimes(graph, passes);
+
+    // The allocations of roots are kept until the end of the graph, including the ones
+    // in-place roots render into.
+    let root_allocations: Vec<NodeId> = graph.roots.iter().map(|&root| graph.in_place_owner(root)).collect();
+
+    for texture in dynamic_textures(passes) {
+        let mut items = Vec::new();
+        for pass in passes {
+            for target in &pass.dynamic_targets {
+                if target.destination != Some(texture) {
+                    continue;
+                }
+                for task in &target.tasks {
+                    let node = &graph.nodes[task.node_id.index()];
+                    if let AllocKind::InPlace(..) = node.alloc_kind {
+                        continue;
+                    }
+                    items.push(PlanItem {
+                        output: NodeOutput::new(task.node_id, task.output),
+                        size: node.alloc_options.allocation_size(node.output_size(task.output)),
+                        lifetime: lifetimes[task.node_id.index()].unwrap(),
+                    });
+                }
+            }
+        }
+
+        // Largest first, then by order of appearance to stay deterministic.
+        items.sort_by_key(|item| std::cmp::Reverse(item.size.area()));
+
+        let max_item_width = items.iter().map(|item| item.size.width).max().unwrap_or(0);
+        let peak_area = peak_live_area(&items);
+        let square_width = (peak_area as f64).sqrt().ceil() as i32;
+
+        let mut best: Option<(i64, Vec<Rectangle>)> = None;
+        for &factor in &[1.0, 1.25, 1.5, 2.0, 3.0] {
+            let width = max_item_width.max((square_width as f32 * factor) as i32);
+            let placements = pack(&items, width);
+            let extents = extents(&placements);
+            let area = extents.width as i64 * extents.height as i64;
+            if best.as_ref().map(|(best_area, _)| area < *best_area).unwrap_or(true) {
+                best = Some((area, placements));
+            }
+        }
+
+        for item in &items {
+            let is_root = root_allocations.contains(&item.output.node);
+            records.push(AllocationRecord {
+                output: item.output,
+                texture,
+                allocated: item.lifetime.first,
+                deallocated: if is_root { None } else { Some(item.lifetime.last + 1) },
+            });
+        }
+
+        if let Some((_, placements)) = best {
+            for (item, allocation) in items.iter().zip(placements.iter()) {
+                let node = &graph.nodes[item.output.node.index()];
+                allocated_rectangles[item.output.node.index()][item.output.index as usize] = node.alloc_options.padded_rectangle(
+                    allocation,
+                    node.output_size(item.output.index),
+                );
+            }
+        }
+    }
+}
+
+struct PlanItem {
+    output: NodeOutput,
+    size: Size,
+    lifetime: Lifetime,
+}
+
+fn dynamic_textures<T>(passes: &[Pass<T>]) -> Vec<TextureId> {
+    let mut textures = Vec::new();
+    for pass in passes {
+        for target in &pass.dynamic_targets {
+            if let Some(texture) = target.destination {
+                if !target.tasks.is_empty() && !textures.contains(&texture) {
+                    textures.push(texture);
+                }
+            }
+        }
+    }
+
+    textures
+}
+
+fn peak_live_area(items: &[PlanItem]) -> i64 {
+    let num_passes = items.iter().map(|item| item.lifetime.last + 1).max().unwrap_or(0);
+    let mut live_area = vec![0i64; num_passes];
+    for item in items {
+        for area in &mut live_area[item.lifetime.first..=item.lifetime.last] {
+            *area += item.size.area() as i64;
+        }
+    }
+
+    live_area.into_iter().max().unwrap_or(0)
+}
+
+/// The top of the items placed in a texture at a given pass, as a list of segments
+/// `(start, height)` sorted by start. Each segment ends where the next one starts, the
+/// last one at the width of the texture.
+#[derive(Clone)]
+struct Skyline {
+    segments: Vec<(i32, i32)>,
+}
+
+impl Skyline {
+    fn new() -> Self {
+        Skyline { segments: vec![(0, 0)] }
+    }
+
+    /// The height of the skyline at `x`.
+    fn height_at(&self, x: i32) -> i32 {
+        let idx = self.segments.partition_point(|&(start, _)| start <= x);
+        self.segments[idx - 1].1
+    }
+
+    /// Take the maximum of both skylines.
+    fn merge(&mut self, other: &Skyline) {
+        let mut starts: Vec<i32> = self.segments.iter()
+            .chain(other.segments.iter())
+            .map(|&(start, _)| start)
+            .collect();
+        starts.sort_unstable();
+        starts.dedup();
+
+        let mut segments: Vec<(i32, i32)> = Vec::with_capacity(starts.len());
+        for start in starts {
+            let height = self.height_at(start).max(other.height_at(start));
+            if segments.last().map(|&(_, h)| h != height).unwrap_or(true) {
+                segments.push((start, height));
+            }
+        }
+        self.segments = segments;
+    }
+
+    /// Raise the skyline to `height` between `start` and `end`.
+    fn raise(&mut self, start: i32, end: i32, height: i32) {
+        let height_after = self.height_at(end);
+        self.segments.retain(|&(x, _)| x < start || x > end);
+        let idx = self.segments.partition_point(|&(x, _)| x < start);
+        self.segments.insert(idx, (start, height));
+        self.segments.insert(idx + 1, (end, height_after));
+        self.segments.dedup_by(|b, a| a.1 == b.1);
+    }
+}
+
+/// Skyline bottom-left packing of rectangles over time within a given width.
+///
+/// Each pass has a skyline of the items that are live during the pass. An item is placed
+/// on the maximum of the skylines of the passes it lives in, at the lowest then leftmost
+/// segment it fits on, and raises these skylines. The space below the skyline that is
+/// left unused is lost, but space is reused once the items above it are not live anymore.
+fn pack(items: &[PlanItem], width: i32) -> Vec<Rectangle> {
+    let num_passes = items.iter().map(|item| item.lifetime.last + 1).max().unwrap_or(0);
+    let mut skylines = vec![Skyline::new(); num_passes];
+    let mut placed = Vec::with_capacity(items.len());
+
+    for item in items {
+        let size = item.size;
+        let lifetime = item.lifetime;
+
+        let mut skyline = skylines[lifetime.first].clone();
+        for other in &skylines[lifetime.first + 1..=lifetime.last] {
+            skyline.merge(other);
+        }
+
+        // The item fits at the start of the first segment since it is at most as large
+        // as the texture.
+        let mut best = (i32::MAX, 0);
+        let segments = &skyline.segments;
+        for (idx, &(x, _)) in segments.iter().enumerate() {
+            if x + size.width > width {
+                break;
+            }
+            let y = segments[idx..].iter()
+                .take_while(|&&(start, _)| start < x + size.width)
+                .map(|&(_, height)| height)
+                .max()
+                .unwrap();
+            if y < best.0 {
+                best = (y, x);
+            }
+        }
+
+        let (y, x) = best;
+        for skyline in &mut skylines[lifetime.first..=lifetime.last] {
+            skyline.raise(x, x + size.width, y + size.height);
+        }
+        placed.push(Rectangle {
+            min: point2(x, y),
+            max: point2(x + size.width, y + size.height),
+        });
+    }
+
+    placed
+}
+
+pub(crate) fn extents(rects: &[Rectangle]) -> Size {
+    let mut extents = size2(0, 0);
+    for rect in rects {
+        extents.width = extents.width.max(rect.max.x);
+        extents.height = extents.height.max(rect.max.y);
+    }
+
+    extents
+}
+
+/// Memory usage of a built graph.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MemoryStats {
+    /// Number of dynamic textures.
+    pub textures: usize,
+    /// Sum of the areas of the dynamic textures, each texture being as large as the
+    /// extents of all of the rectangles allocated in it.
+    ///
+    /// This is the peak memory needed for the dynamic textures since they all live for
+    /// the duration of the graph.
+    pub texture_pixels: i64,
+    /// Maximum number of pixels used by live dynamic allocations during a single pass.
+    pub live_pixels: i64,
+}
+
+pub(crate) fn memory_stats<T, U>(
+    graph: &Graph<T, U>,
+    passes: &[Pass<T>],
+    allocated_rectangles: &[NodeRectangles],
+) -> MemoryStats {
+    let lifetimes = node_lifetimes(graph, passes);
+    let mut stats = MemoryStats::default();
+    let mut live_pixels = vec![0i64; passes.len()];
+
+    for texture in dynamic_textures(passes) {
+        let mut rects = Vec::new();
+        for pass in passes {
+            for target in &pass.dynamic_targets {
+                if target.destination != Some(texture) {
+                    continue;
+                }
+                for task in &target.tasks {
+                    if let AllocKind::InPlace(..) = graph.nodes[task.node_id.index()].alloc_kind {
+                        continue;
+                    }
+                    let rect = allocated_rectangles[task.node_id.index()][task.output as usize];
+                    let lifetime = lifetimes[task.node_id.index()].unwrap();
+                    for pixels in &mut live_pixels[lifetime.first..=lifetime.last] {
+                        *pixels += rect.area() as i64;
+                    }
+                    rects.push(rect);
+                }
+            }
+        }
+
+        let size = extents(&rects);
+        stats.textures += 1;
+        stats.texture_pixels += size.width as i64 * size.height as i64;
+    }
+
+    stats.live_pixels = live_pixels.into_iter().max().unwrap_or(0);
+
+    stats
+}
@@ -0,0 +1,279 @@
+use crate::graph::*;
+use crate::Size;
+
+enum Item<T> {
+    /// Placeholder for a node of the parent graph, provided at instantiation.
+    Input(usize),
+    Node(Node<T>),
+}
+
+/// A reusable piece of graph, for example the nodes that render a picture.
+///
+/// Nodes are added through the `GraphBuilder` trait like in a regular graph, using
+/// the ids returned by `add_input` to depend on nodes that are not part of the
+/// subgraph. One of the nodes is declared as the output, which the parent graph
+/// depends on.
+///
+/// The same subgraph can be instantiated several times, in the same parent graph or
+/// in different ones. The node ids of the subgraph are only meaningful within it.
+pub struct SubGraph<T = TaskId, U = ()> {
+    items: Vec<Item<T>>,
+    /// Indexed by node. Can be shorter than `items`.
+    user_data: Vec<Option<U>>,
+    num_inputs: usize,
+    roots: Vec<NodeId>,
+    side_effects: Vec<NodeId>,
+    output: Option<NodeId>,
+}
+
+impl<T, U> Default for SubGraph<T, U> {
+    fn default() -> Self {
+        SubGraph {
+            items: Vec::new(),
+            user_data: Vec::new(),
+            num_inputs: 0,
+            roots: Vec::new(),
+            side_effects: Vec::new(),
+            output: None,
+        }
+    }
+}
+
+impl<T> SubGraph<T> {
+    /// Subgraphs with user data can be created with `SubGraph::default`.
+    pub fn new() -> Self {
+        SubGraph::default()
+    }
+}
+
+impl<T, U> SubGraph<T, U> {
+    /// Declare a node that is provided by the parent graph when the subgraph is
+    /// instantiated.
+    ///
+    /// Inputs are provided to `instantiate` in the order they were added.
+    pub fn add_input(&mut self) -> NodeId {
+        let id = node_id(self.items.len());
+        self.items.push(Item::Input(self.num_inputs));
+        self.num_inputs += 1;
+
+        id
+    }
+
+    pub fn num_inputs(&self) -> usize {
+        self.num_inputs
+    }
+
+    pub fn set_output(&mut self, node: NodeId) {
+        assert!(matches!(self.items[node.index()], Item::Node(_)), "The output of a subgraph can't be an input");
+        self.output = Some(node);
+    }
+
+    pub fn output(&self) -> Option<NodeId> {
+        self.output
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> &mut Node<T> {
+        match &mut self.items[id.index()] {
+            Item::Node(node) => node,
+            Item::Input(_) => panic!("Inputs of a subgraph can't be modified"),
+        }
+    }
+
+    /// Add the nodes of the subgraph to a parent graph and return the id of the output
+    /// node in the parent graph.
+    ///
+    /// `inputs` are the nodes of the parent graph that replace the inputs of the subgraph.
+    /// Roots and side effects of the subgraph become roots and side effects of the parent
+    /// graph, and each instance gets a copy of the user data.
+    pub fn instantiate(&self, parent: &mut dyn GraphBuilder<T, U>, inputs: &[NodeId]) -> NodeId
+    where
+        T: Clone,
+        U: Clone,
+    {
+        assert_eq!(inputs.len(), self.num_inputs, "Wrong number of inputs");
+        let output = self.output.expect("The subgraph has no output");
+
+        // Dependencies are added once all nodes exist since they may reference nodes
+        // added later.
+        let mut ids = Vec::with_capacity(self.items.len());
+        for item in &self.items {
+            ids.push(match item {
+                Item::Input(idx) => inputs[*idx],
+                Item::Node(node) => {
+                    let alloc_kind = match node.alloc_kind {
+                        AllocKind::InPlace(dep) => {
+                            assert!(dep.index() < ids.len(), "In-place nodes must be added after the node they render into");
+                            AllocKind::InPlace(ids[dep.index()])
+                        }
+                        alloc_kind => alloc_kind,
+                    };
+                    let id = parent.add_node(node.task_id.clone(), node.target_kind, node.size, alloc_kind, &[]);
+                    for output in &node.extra_outputs {
+                        parent.add_output(id, output.target_kind, output.size);
+                    }
+                    if node.alloc_options != AllocationOptions::default() {
+                        parent.set_allocation_options(id, node.alloc_options);
+                    }
+                    if let Some(label) = &node.label {
+                        parent.set_label(id, label.clone());
+                    }
+                    if let Some(Some(data)) = self.user_data.get(ids.len()) {
+                        parent.set_user_data(id, data.clone());
+                    }
+                    id
+                }
+            });
+        }
+
+        for (item, &id) in self.items.iter().zip(ids.iter()) {
+            if let Item::Node(node) = item {
+                for dep in &node.dependencies {
+                    parent.add_dependency(id, ids[dep.index()]);
+                }
+                for output in &node.output_dependencies {
+                    parent.add_output_dependency(id, NodeOutput::new(ids[output.node.index()], output.index));
+                }
+            }
+        }
+
+        for root in &self.roots {
+            parent.add_root(ids[root.index()]);
+        }
+        for node in &self.side_effects {
+            parent.add_side_effect(ids[node.index()]);
+        }
+
+        ids[output.index()]
+    }
+}
+
+impl<T, U> GraphBuilder<T, U> for SubGraph<T, U> {
+    fn add_node(
+        &mut self,
+        task_id: T,
+        target_kind: TargetKind,
+        size: Size,
+        alloc_kind: AllocKind,
+        deps: &[NodeId]
+    ) -> NodeId {
+        let id = node_id(self.items.len());
+        self.items.push(Item::Node(Node::new(task_id, target_kind, size, alloc_kind, deps)));
+
+        id
+    }
+
+    fn add_dependency(&mut self, node: NodeId, dep: NodeId) {
+        self.node_mut(node).dependencies.push(dep);
+    }
+
+    fn add_root(&mut self, node: NodeId) {
+        self.roots.push(node);
+    }
+
+    fn add_side_effect(&mut self, node: NodeId) {
+        self.side_effects.push(node);
+    }
+
+    fn set_allocation_options(&mut self, node: NodeId, options: AllocationOptions) {
+        self.node_mut(node).alloc_options = options;
+    }
+
+    fn set_label(&mut self, node: NodeId, label: String) {
+        self.node_mut(node).label = Some(label);
+    }
+
+    fn add_output(&mut self, node: NodeId, target_kind: TargetKind, size: Size) -> NodeOutput {
+        let outputs = &mut self.node_mut(node).extra_outputs;
+        outputs.push(OutputDescriptor { target_kind, size });
+
+        NodeOutput::new(node, outputs.len() as u32)
+    }
+
+    fn add_output_dependency(&mut self, node: NodeId, output: NodeOutput) {
+        if output.index == 0 {
+            self.add_dependency(node, output.node);
+        } else {
+            self.node_mut(node).output_dependencies.push(output);
+        }
+    }
+
+    fn set_user_data(&mut self, node: NodeId, data: U) {
+        // Panics if the node is an input.
+        self.node_mut(node);
+        let idx = node.index();
+        if idx >= self.user_data.len() {
+            self.user_data.resize_with(idx + 1, || None);
+        }
+        self.user_data[idx] = Some(data);
+    }
+}
+
+#[test]
+fn instantiate_subgraph() {
+    use euclid::size2;
+
+    // A blur applied to whatever picture is provided as input.
+    let mut blur = SubGraph::new();
+    let input = blur.add_input();
+    let downscale = blur.add_node(TaskId::Render(1, 0), TargetKind::Color, size2(50, 50), AllocKind::Dynamic, &[input]);
+    let vblur = blur.add_node(TaskId::Render(1, 1), TargetKind::Color, size2(50, 50), AllocKind::Dynamic, &[downscale]);
+    let hblur = blur.add_node(TaskId::Render(1, 2), TargetKind::Color, size2(50, 50), AllocKind::Dynamic, &[vblur]);
+    blur.set_allocation_options(hblur, AllocationOptions { padding: 2, .. AllocationOptions::default() });
+    blur.set_label(hblur, "blur".to_string());
+    blur.set_output(hblur);
+
+    // Subgraphs can be instantiated into other subgraphs.
+    let mut picture = SubGraph::new();
+    let content = picture.add_node(TaskId::Render(2, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let blurred = blur.instantiate(&mut picture, &[content]);
+    let composite = picture.add_node(TaskId::Render(2, 1), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[content]);
+    picture.add_dependency(composite, blurred);
+    picture.set_output(composite);
+
+    let mut graph = Graph::new();
+    let a = picture.instantiate(&mut graph, &[]);
+    let b = picture.instantiate(&mut graph, &[]);
+    let root = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(200, 100), AllocKind::Dynamic, &[a, b]);
+    graph.add_root(root);
+
+    assert_eq!(graph.num_nodes(), 11);
+    assert_ne!(a, b);
+    for &output in &[a, b] {
+        let deps = graph.node_dependencies(output);
+        assert_eq!(deps.len(), 2);
+        let content = deps[0];
+        let hblur = deps[1];
+        assert_eq!(graph[content].task_id, TaskId::Render(2, 0));
+        assert_eq!(graph[hblur].task_id, TaskId::Render(1, 2));
+        assert_eq!(graph[hblur].alloc_options.padding, 2);
+        assert_eq!(graph.label(hblur), Some("blur"));
+        let vblur = graph.node_dependencies(hblur)[0];
+        let downscale = graph.node_dependencies(vblur)[0];
+        assert_eq!(graph.node_dependencies(downscale), &[content]);
+    }
+
+    let mut allocator = crate::GuillotineAllocator::new(size2(1024, 1024));
+    graph.build(&BuilderOptions::default(), &mut allocator).unwrap();
+}
+
+#[test]
+fn instantiate_user_data() {
+    use euclid::size2;
+
+    let mut sub: SubGraph<TaskId, &str> = SubGraph::default();
+    let input = sub.add_input();
+    let a = sub.add_node(TaskId::Render(1, 0), TargetKind::Color, size2(50, 50), AllocKind::Dynamic, &[input]);
+    let b = sub.add_node(TaskId::Render(1, 1), TargetKind::Color, size2(50, 50), AllocKind::Dynamic, &[a]);
+    sub.set_user_data(b, "blur");
+    sub.set_output(b);
+
+    let mut graph = Graph::default();
+    let content = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let first = sub.instantiate(&mut graph, &[content]);
+    let second = sub.instantiate(&mut graph, &[content]);
+
+    assert_eq!(graph.user_data(first), Some(&"blur"));
+    assert_eq!(graph.user_data(second), Some(&"blur"));
+    assert_eq!(graph.user_data(graph.node_dependencies(first)[0]), None);
+    assert_eq!(graph.user_data(content), None);
+}
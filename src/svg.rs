@@ -1,30 +1,42 @@
 use std::io::Write;
 use euclid::{point2, vec2, size2};
 use crate::{FloatPoint, Rectangle, FloatRectangle, FloatSize};
-use crate::{GuillotineAllocator, BuiltGraph, NodeId};
+use crate::{BuiltGraph, NodeId, NodeOutput, TargetKind, TextureId};
 
 pub fn rectangle(output: &mut dyn Write, rect: &FloatRectangle, radius: f32, style: &str) {
+    rectangle_with_attributes(output, rect, radius, style, "");
+}
+
+/// Same as `rectangle` with extra attributes, for example a class or data attributes.
+pub(crate) fn rectangle_with_attributes(output: &mut dyn Write, rect: &FloatRectangle, radius: f32, style: &str, attributes: &str) {
     write!(output,
-        r#"    <rect ry="{}" x="{}" y="{}" width="{}" height="{}" style="{}" />"#,
+        r#"    <rect ry="{}" x="{}" y="{}" width="{}" height="{}" style="{}"{} />"#,
         radius,
         rect.min.x,
         rect.min.y,
         rect.size().width,
         rect.size().height,
-        style
+        style,
+        attributes,
     ).unwrap();
 }
 
 pub fn text(output: &mut dyn Write, text: &str, size: f32, position: FloatPoint, style: &str) {
+    text_with_attributes(output, text, size, position, style, "");
+}
+
+/// Same as `text` with extra attributes.
+pub(crate) fn text_with_attributes(output: &mut dyn Write, text: &str, size: f32, position: FloatPoint, style: &str, attributes: &str) {
     write!(output,
 r#"
-    <text x="{}" y="{}" style="font-style:normal;font-weight:normal;font-size:{}px;line-height:1.25;font-family:sans-serif;stroke:none;{}">
+    <text x="{}" y="{}" style="font-style:normal;font-weight:normal;font-size:{}px;line-height:1.25;font-family:sans-serif;stroke:none;{}"{}>
         <tspan>{}</tspan>
     </text>
 "#,
         position.x, position.y,
         size,
         style,
+        attributes,
         text,
     ).unwrap();
 }
@@ -54,6 +66,11 @@ pub fn end_svg(output: &mut dyn Write) {
 }
 
 pub fn link(output: &mut dyn Write, from: FloatPoint, to: FloatPoint, style: &str) {
+    link_with_attributes(output, from, to, style, "");
+}
+
+/// Same as `link` with extra attributes.
+pub(crate) fn link_with_attributes(output: &mut dyn Write, from: FloatPoint, to: FloatPoint, style: &str, attributes: &str) {
 
     // If the link is a straight horizontal line and spans over multiple passes, it
     // is likely to go stright htough unrlated nodes in a way that makes it look like
@@ -64,13 +81,14 @@ pub fn link(output: &mut dyn Write, from: FloatPoint, to: FloatPoint, style: &st
     if simple_path {
         write!(output,
     r#"
-        <path d="M {} {} C {} {} {} {} {} {}" style="fill:none;{}" />
+        <path d="M {} {} C {} {} {} {} {} {}" style="fill:none;{}"{} />
     "#,
             from.x, from.y,
             mid.x, from.y,
             mid.x, to.y,
             to.x, to.y,
             style,
+            attributes,
         ).unwrap();
     } else {
         let ctrl1 = from.lerp(mid, 0.5) - vec2(0.0, 25.0);
@@ -78,7 +96,7 @@ pub fn link(output: &mut dyn Write, from: FloatPoint, to: FloatPoint, style: &st
         let mid = mid - vec2(0.0, 25.0);
         write!(output,
     r#"
-        <path d="M {} {} C {} {} {} {} {} {} C {} {} {} {} {} {}" style="fill:none;{}" />
+        <path d="M {} {} C {} {} {} {} {} {} C {} {} {} {} {} {}" style="fill:none;{}"{} />
     "#,
             from.x, from.y,
             ctrl1.x, ctrl1.y,
@@ -88,10 +106,213 @@ pub fn link(output: &mut dyn Write, from: FloatPoint, to: FloatPoint, style: &st
             ctrl2.x, ctrl2.y,
             to.x, to.y,
             style,
+            attributes,
         ).unwrap();
     }
 }
 
+/// A link through a sequence of points, alternating between curves and straight
+/// horizontal lines.
+///
+/// The link curves from the first point to the second, goes straight to the third,
+/// curves to the fourth and so on.
+pub(crate) fn routed_link_with_attributes(output: &mut dyn Write, points: &[FloatPoint], style: &str, attributes: &str) {
+    let mut path = format!("M {} {}", points[0].x, points[0].y);
+    for (idx, segment) in points.windows(2).enumerate() {
+        let (from, to) = (segment[0], segment[1]);
+        if idx % 2 == 0 {
+            let mid_x = (from.x + to.x) / 2.0;
+            path.push_str(&format!(" C {} {} {} {} {} {}", mid_x, from.y, mid_x, to.y, to.x, to.y));
+        } else {
+            path.push_str(&format!(" L {} {}", to.x, to.y));
+        }
+    }
+
+    write!(output,
+    r#"
+        <path d="{}" style="fill:none;{}"{} />
+    "#,
+        path,
+        style,
+        attributes,
+    ).unwrap();
+}
+
+/// Replace the characters that have a special meaning in XML.
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// The colors of the visualization, in any format accepted by CSS.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SvgTheme {
+    pub background: String,
+    pub target_background: String,
+    pub node: String,
+    /// Nodes rendering into color targets with `NodeColors::TargetKind`.
+    pub color_target_node: String,
+    /// Nodes rendering into alpha targets with `NodeColors::TargetKind`.
+    pub alpha_target_node: String,
+    pub node_label: String,
+    /// The task and size of the nodes.
+    pub node_details: String,
+    pub link: String,
+    pub texture_label: String,
+    pub atlas_background: String,
+    pub allocation: String,
+}
+
+impl SvgTheme {
+    pub fn dark() -> Self {
+        SvgTheme {
+            background: "rgb(50,50,50)".to_string(),
+            target_background: "black".to_string(),
+            node: "rgb(200,200,200)".to_string(),
+            color_target_node: "rgb(210,180,130)".to_string(),
+            alpha_target_node: "rgb(150,190,220)".to_string(),
+            node_label: "black".to_string(),
+            node_details: "rgb(50,50,50)".to_string(),
+            link: "rgb(100,100,100)".to_string(),
+            texture_label: "rgb(250,250,250)".to_string(),
+            atlas_background: "black".to_string(),
+            allocation: "rgb(50,70,180)".to_string(),
+        }
+    }
+
+    pub fn light() -> Self {
+        SvgTheme {
+            background: "white".to_string(),
+            target_background: "rgb(100,100,120)".to_string(),
+            node: "rgb(250,250,250)".to_string(),
+            color_target_node: "rgb(250,220,170)".to_string(),
+            alpha_target_node: "rgb(190,220,250)".to_string(),
+            node_label: "black".to_string(),
+            node_details: "rgb(80,80,80)".to_string(),
+            link: "rgb(160,160,170)".to_string(),
+            texture_label: "rgb(30,30,30)".to_string(),
+            atlas_background: "rgb(80,80,90)".to_string(),
+            allocation: "rgb(90,130,230)".to_string(),
+        }
+    }
+}
+
+impl Default for SvgTheme {
+    fn default() -> Self {
+        SvgTheme::dark()
+    }
+}
+
+/// How the boxes of the nodes are filled.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NodeColors {
+    /// All nodes have the `node` color of the theme.
+    Uniform,
+    /// Nodes are colored by the target kind of their main output.
+    TargetKind,
+    /// Nodes are colored by the category of their task, which is the name of the task
+    /// type or variant in its `Debug` representation, for example `Render` in
+    /// `Render(0, 1)`.
+    TaskCategory,
+}
+
+/// Parameters of `dump_svg_with_options`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SvgOptions {
+    pub theme: SvgTheme,
+    pub node_colors: NodeColors,
+    pub node_width: f32,
+    pub node_height: f32,
+    /// Space between the nodes of a pass.
+    pub vertical_spacing: f32,
+    /// Space between the passes.
+    pub horizontal_spacing: f32,
+    /// Font size of the node labels, the task and size of the nodes are smaller.
+    pub font_size: f32,
+    pub link_width: f32,
+    /// Show a preview of the allocated rectangles under the nodes of each target.
+    pub show_atlases: bool,
+    pub show_task_ids: bool,
+    pub show_sizes: bool,
+}
+
+impl SvgOptions {
+    /// Dark background with all of the details.
+    pub fn dark() -> Self {
+        SvgOptions {
+            theme: SvgTheme::dark(),
+            node_colors: NodeColors::Uniform,
+            node_width: 80.0,
+            node_height: 40.0,
+            vertical_spacing: 10.0,
+            horizontal_spacing: 40.0,
+            font_size: 10.0,
+            link_width: 3.0,
+            show_atlases: true,
+            show_task_ids: true,
+            show_sizes: true,
+        }
+    }
+
+    /// Light background, for example for documentation.
+    pub fn light() -> Self {
+        SvgOptions {
+            theme: SvgTheme::light(),
+            .. SvgOptions::dark()
+        }
+    }
+
+    /// Small nodes showing only their label, for large graphs.
+    pub fn compact() -> Self {
+        SvgOptions {
+            node_colors: NodeColors::TargetKind,
+            node_width: 40.0,
+            node_height: 12.0,
+            vertical_spacing: 4.0,
+            horizontal_spacing: 20.0,
+            font_size: 6.0,
+            link_width: 1.0,
+            show_atlases: false,
+            show_task_ids: false,
+            show_sizes: false,
+            .. SvgOptions::dark()
+        }
+    }
+}
+
+impl Default for SvgOptions {
+    fn default() -> Self {
+        SvgOptions::dark()
+    }
+}
+
+/// The part of the debug representation of a task before its parameters.
+fn task_category(task: &str) -> &str {
+    task.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':')).next().unwrap()
+}
+
+/// A color that only depends on the category, so that it is the same in every graph.
+fn category_color(category: &str) -> String {
+    // FNV-1a, to not depend on the hasher of the standard library.
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in category.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+
+    format!("hsl({}, 50%, 70%)", hash % 360)
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct VerticalLayout {
     pub start: FloatPoint,
@@ -134,157 +355,697 @@ impl VerticalLayout {
     }
 }
 
-pub fn dump_svg<'l>(
-    output: &mut dyn std::io::Write,
-    graph: &BuiltGraph,
-    allocator: &GuillotineAllocator,
-    names: Option<&'l dyn Fn(NodeId) -> &'l str>,
-) {
-    let node_width = 80.0;
-    let node_height = 40.0;
-    let texture_box_height = 15.0;
-    let vertical_spacing = 10.0;
-    let horizontal_spacing = 40.0;
-    let margin = 10.0;
+/// A render target of a pass and the rectangles allocated in it.
+pub(crate) struct TargetLayout {
+    pub pass: usize,
+    /// The box around the nodes and the atlas of the target.
+    pub rect: FloatRectangle,
+    /// The texture label, right above the atlas.
+    pub label_rect: FloatRectangle,
+    pub destination: Option<TextureId>,
+    pub allocations: Vec<(NodeOutput, Rectangle)>,
+    pub texture_size: FloatSize,
+}
+
+/// A link between two nodes, going through the gaps between the nodes of the columns it
+/// spans.
+pub(crate) struct LinkLayout {
+    pub from: NodeId,
+    pub to: NodeId,
+    /// The start and end of the link with, in between, the points where the link enters
+    /// and leaves each column it crosses.
+    pub points: Vec<FloatPoint>,
+}
+
+/// Position of the elements of the visualization of a built graph.
+///
+/// Each pass is a column of targets, with the nodes of a target above a preview of its
+/// texture.
+pub(crate) struct Layout {
+    pub size: FloatSize,
+    pub node_width: f32,
+    pub texture_box_height: f32,
+    /// The box of each node that is part of a pass or is an external input.
+    pub nodes: Vec<Option<FloatRectangle>>,
+    /// The pass of each node, `None` for external inputs and culled nodes.
+    pub node_passes: Vec<Option<usize>>,
+    pub targets: Vec<TargetLayout>,
+    pub links: Vec<LinkLayout>,
+}
+
+/// Nodes that are shown next to each other and can be reordered.
+struct NodeGroup {
+    /// The boxes of the group, from top to bottom.
+    slots: Vec<FloatRectangle>,
+    nodes: Vec<NodeId>,
+}
+
+/// A column of nodes and atlases that links must not cross.
+struct Column {
+    x: f32,
+    obstacles: Vec<FloatRectangle>,
+    /// The vertical position of the links already going through the column.
+    lanes: Vec<f32>,
+}
 
-    let mut target_rects = Vec::new();
-    let mut texture_info = Vec::new();
-    let mut node_label_rects = vec![None; graph.num_nodes()];
-    let mut x = margin;
-    let mut max_y: f32 = 0.0;
-    for pass in graph.passes() {
-        let mut layout = VerticalLayout::new(point2(x, margin), node_width);
-        for target in &pass.dynamic_targets {
-            if target.tasks.is_empty() {
+impl Column {
+    /// Find a position to cross the column as close as possible to `y`, between
+    /// obstacles and away from the other links.
+    fn free_lane(&self, y: f32, height: f32, padding: f32, lane_spacing: f32) -> f32 {
+        let mut obstacles: Vec<(f32, f32)> = self.obstacles.iter().map(|r| (r.min.y, r.max.y)).collect();
+        obstacles.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+        let mut gaps = Vec::with_capacity(obstacles.len() + 1);
+        let mut top = 0.0;
+        for (min, max) in obstacles {
+            if min > top {
+                gaps.push((top, min));
+            }
+            top = top.max(max);
+        }
+        gaps.push((top, height.max(top)));
+
+        let is_free = |y: f32| self.lanes.iter().all(|lane| (lane - y).abs() >= lane_spacing);
+        let mut best: Option<f32> = None;
+        let mut fallback = None;
+        for (top, bottom) in gaps {
+            let (top, bottom) = (top + padding, bottom - padding);
+            if top > bottom {
                 continue;
             }
 
-            layout.start_here();
-            let mut allocated_rects = Vec::new();
-            for task in &target.tasks {
-                node_label_rects[task.node_id.index()] = Some(layout.push_rectangle(node_height));
+            // Search outward from the closest point of the gap.
+            let start = y.max(top).min(bottom);
+            if fallback.map(|f: f32| (start - y).abs() < (f - y).abs()).unwrap_or(true) {
+                fallback = Some(start);
+            }
+            let mut offset = 0.0;
+            'search: while offset <= bottom - top {
+                for &candidate in &[start - offset, start + offset] {
+                    if candidate >= top && candidate <= bottom && is_free(candidate) {
+                        if best.map(|b| (candidate - y).abs() < (b - y).abs()).unwrap_or(true) {
+                            best = Some(candidate);
+                        }
+                        break 'search;
+                    }
+                }
+                offset += lane_spacing;
+            }
+        }
+
+        best.or(fallback).unwrap_or(y)
+    }
+}
+
+/// The number of times nodes are reordered, alternating between ordering nodes by the
+/// position of their inputs and by the position of the nodes that depend on them.
+const ORDERING_SWEEPS: usize = 5;
+
+impl Layout {
+    pub fn new<T, U>(graph: &BuiltGraph<T, U>, options: &SvgOptions) -> Self {
+        let node_width = options.node_width;
+        let node_height = options.node_height;
+        let texture_box_height = if options.show_atlases { 15.0 } else { 0.0 };
+        let vertical_spacing = options.vertical_spacing;
+        let horizontal_spacing = options.horizontal_spacing;
+        let margin = 10.0;
+
+        let mut targets = Vec::new();
+        let mut nodes = vec![None; graph.num_nodes()];
+        let mut node_passes = vec![None; graph.num_nodes()];
+        let mut node_columns = vec![None; graph.num_nodes()];
+        let mut groups = Vec::new();
+        let mut columns = Vec::new();
+        let mut x = margin;
+        let mut max_y: f32 = 0.0;
+
+        // External inputs are in their own column before the passes.
+        if !graph.inputs().is_empty() {
+            let mut layout = VerticalLayout::new(point2(x, margin), node_width);
+            let mut group = NodeGroup { slots: Vec::new(), nodes: Vec::new() };
+            for &input in graph.inputs() {
+                group.slots.push(layout.push_rectangle(node_height));
+                group.nodes.push(input);
+                node_columns[input.index()] = Some(columns.len());
                 layout.advance(vertical_spacing);
-                allocated_rects.push(graph.allocated_rectangle(task.node_id));
             }
+            groups.push(group);
+            columns.push(Column { x, obstacles: Vec::new(), lanes: Vec::new() });
+            x += node_width + horizontal_spacing;
+            max_y = max_y.max(layout.y + 100.0);
+        }
 
-            let texture_label_rect = layout.push_rectangle(texture_box_height);
-            let tex_size = allocator.textures[target.destination.unwrap().index()].size().to_f32();
-            let scale = tex_size.width / node_width;
-            layout.push_rectangle(tex_size.height / scale);
+        for (pass_index, pass) in graph.passes().iter().enumerate() {
+            let mut layout = VerticalLayout::new(point2(x, margin), node_width);
+            let mut column = Column { x, obstacles: Vec::new(), lanes: Vec::new() };
+            for target in &pass.dynamic_targets {
+                if target.tasks.is_empty() {
+                    continue;
+                }
 
-            target_rects.push(layout.total_rectangle().inflate(5.0, 5.0));
+                layout.start_here();
+                let mut allocations = Vec::new();
+                let mut group = NodeGroup { slots: Vec::new(), nodes: Vec::new() };
+                for task in &target.tasks {
+                    // Nodes are shown once, with their main output.
+                    if task.output == 0 {
+                        group.slots.push(layout.push_rectangle(node_height));
+                        group.nodes.push(task.node_id);
+                        node_passes[task.node_id.index()] = Some(pass_index);
+                        node_columns[task.node_id.index()] = Some(columns.len());
+                        layout.advance(vertical_spacing);
+                    }
+                    let output = NodeOutput::new(task.node_id, task.output);
+                    allocations.push((output, *graph.output_rectangle(output)));
+                }
+                // The target may only hold additional outputs of nodes shown elsewhere.
+                if !group.nodes.is_empty() {
+                    groups.push(group);
+                }
 
-            layout.advance(vertical_spacing * 2.0);
+                let label_rect = layout.push_rectangle(texture_box_height);
+                let texture_size = graph.texture_size(target.destination.unwrap()).unwrap().to_f32();
+                if options.show_atlases {
+                    let scale = texture_size.width / node_width;
+                    let atlas_rect = layout.push_rectangle(texture_size.height / scale);
+                    column.obstacles.push(label_rect.union(&atlas_rect));
+                }
+
+                targets.push(TargetLayout {
+                    pass: pass_index,
+                    rect: layout.total_rectangle().inflate(5.0, 5.0),
+                    label_rect,
+                    destination: target.destination,
+                    allocations,
+                    texture_size,
+                });
+
+                layout.advance(vertical_spacing * 2.0);
+            }
+
+            for target in &pass.fixed_targets {
+                layout.start_here();
+                let mut allocations = Vec::new();
+                let mut union_rect = Rectangle::zero();
+                let mut group = NodeGroup { slots: Vec::new(), nodes: Vec::new() };
+                for task in &target.tasks {
+                    group.slots.push(layout.push_rectangle(node_height));
+                    group.nodes.push(task.node_id);
+                    node_passes[task.node_id.index()] = Some(pass_index);
+                    node_columns[task.node_id.index()] = Some(columns.len());
+                    layout.advance(vertical_spacing);
+                    let r = *graph.allocated_rectangle(task.node_id);
+                    allocations.push((NodeOutput::main(task.node_id), r));
+                    union_rect = union_rect.union(&r);
+                }
+                groups.push(group);
+
+                let label_rect = layout.push_rectangle(texture_box_height);
+                let texture_size = union_rect.size().to_f32();
+                if options.show_atlases {
+                    let scale = texture_size.width / node_width;
+                    let atlas_rect = layout.push_rectangle(texture_size.height / scale);
+                    column.obstacles.push(label_rect.union(&atlas_rect));
+                }
+
+                targets.push(TargetLayout {
+                    pass: pass_index,
+                    rect: layout.total_rectangle().inflate(5.0, 5.0),
+                    label_rect,
+                    destination: target.destination,
+                    allocations,
+                    texture_size,
+                });
+
+                layout.advance(vertical_spacing * 2.0);
+            }
 
-            texture_info.push((
-                texture_label_rect,
-                target.destination,
-                allocated_rects,
-                tex_size,
-            ));
+            columns.push(column);
+            x += node_width + horizontal_spacing;
+            max_y = max_y.max(layout.y + 100.0);
         }
 
-        for target in &pass.fixed_targets {
-            layout.start_here();
-            let mut allocated_rects = Vec::new();
-            let mut union_rect = Rectangle::zero();
-            for task in &target.tasks {
-                node_label_rects[task.node_id.index()] = Some(layout.push_rectangle(node_height));
-                layout.advance(vertical_spacing);
-                let r = graph.allocated_rectangle(task.node_id);
-                allocated_rects.push(r);
-                union_rect = union_rect.union(&r);
+        for group in &groups {
+            for (&node, &slot) in group.nodes.iter().zip(group.slots.iter()) {
+                nodes[node.index()] = Some(slot);
             }
+        }
+
+        order_nodes(graph, &mut groups, &mut nodes);
 
-            let texture_label_rect = layout.push_rectangle(texture_box_height);
-            let tex_size = union_rect.size().to_f32();
-            let scale = tex_size.width / node_width;
-            layout.push_rectangle(tex_size.height / scale);
+        for group in &groups {
+            let column = node_columns[group.nodes[0].index()].unwrap();
+            columns[column].obstacles.extend_from_slice(&group.slots);
+        }
+
+        let size = size2(x + margin, max_y + margin);
 
-            target_rects.push(layout.total_rectangle().inflate(5.0, 5.0));
+        // Links that span several passes go through the gaps between the obstacles of
+        // the columns in between instead of across unrelated nodes.
+        let padding = options.link_width;
+        let lane_spacing = options.link_width * 2.0;
+        let mut links = Vec::new();
+        for id in graph.node_ids() {
+            let (to_rect, to_column) = match (nodes[id.index()], node_columns[id.index()]) {
+                (Some(rect), Some(column)) => (rect, column),
+                _ => continue,
+            };
+            for input in graph.node_inputs(id) {
+                let from_rect = nodes[input.node.index()].unwrap();
+                let from_column = node_columns[input.node.index()].unwrap();
+                let from = point2(from_rect.max.x, from_rect.center().y);
+                let to = point2(to_rect.min.x, to_rect.center().y);
 
-            layout.advance(vertical_spacing * 2.0);
+                let mut points = vec![from];
+                let mut y = from.y;
+                for column in columns.iter_mut().take(to_column).skip(from_column + 1) {
+                    y = column.free_lane(y, size.height, padding, lane_spacing);
+                    column.lanes.push(y);
+                    points.push(point2(column.x - 5.0, y));
+                    points.push(point2(column.x + node_width + 5.0, y));
+                }
+                points.push(to);
 
-            texture_info.push((
-                texture_label_rect,
-                target.destination,
-                allocated_rects,
-                tex_size,
-            ));
+                links.push(LinkLayout { from: input.node, to: id, points });
+            }
         }
 
-        x += node_width + horizontal_spacing;
-        max_y = max_y.max(layout.y + 100.0);
+        Layout {
+            size,
+            node_width,
+            texture_box_height,
+            nodes,
+            node_passes,
+            targets,
+            links,
+        }
     }
+}
+
+/// Reorder the nodes within their group to reduce the number of crossing links, using
+/// the barycenter heuristic: each node is moved towards the average position of the
+/// nodes it is linked to in the previous (or next) columns.
+fn order_nodes<T, U>(
+    graph: &BuiltGraph<T, U>,
+    groups: &mut [NodeGroup],
+    nodes: &mut [Option<FloatRectangle>],
+) {
+    let mut inputs = vec![Vec::new(); nodes.len()];
+    let mut dependents = vec![Vec::new(); nodes.len()];
+    for id in graph.node_ids() {
+        if nodes[id.index()].is_none() {
+            continue;
+        }
+        for input in graph.node_inputs(id) {
+            inputs[id.index()].push(input.node);
+            dependents[input.node.index()].push(id);
+        }
+    }
+
+    for sweep in 0..ORDERING_SWEEPS {
+        // Groups are sorted by column, so that the neighbors of the nodes of a group
+        // have already been ordered in this sweep.
+        let forward = sweep % 2 == 0;
+        let neighbors = if forward { &inputs } else { &dependents };
+        let mut group_indices: Vec<usize> = (0..groups.len()).collect();
+        if !forward {
+            group_indices.reverse();
+        }
+
+        for group_index in group_indices {
+            let group = &mut groups[group_index];
+            let mut keys: Vec<(f32, NodeId)> = group.nodes.iter().map(|&id| {
+                let positions: Vec<f32> = neighbors[id.index()].iter()
+                    .filter_map(|neighbor| nodes[neighbor.index()])
+                    .map(|rect| rect.center().y)
+                    .collect();
+                let key = if positions.is_empty() {
+                    nodes[id.index()].unwrap().center().y
+                } else {
+                    positions.iter().sum::<f32>() / positions.len() as f32
+                };
+                (key, id)
+            }).collect();
+            keys.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+            for (idx, (_, id)) in keys.into_iter().enumerate() {
+                group.nodes[idx] = id;
+                nodes[id.index()] = Some(group.slots[idx]);
+            }
+        }
+    }
+}
+
+/// Write the elements of the visualization of a built graph.
+///
+/// If `interactive` is true, elements are annotated so that scripts can find them: node
+/// boxes have the `node` class, links the `link` class and atlas rectangles the
+/// `allocation` class, with `data-node`, `data-from`, `data-to` and `data-output`
+/// attributes referring to node indices and a `data-pass` attribute listing the passes
+/// an element belongs to.
+pub(crate) fn write_graph<T: std::fmt::Debug, U>(
+    output: &mut dyn Write,
+    graph: &BuiltGraph<T, U>,
+    layout: &Layout,
+    options: &SvgOptions,
+    interactive: bool,
+) {
+    let theme = &options.theme;
+    let node_width = layout.node_width;
+
+    let pass_attribute = |passes: &[Option<usize>]| -> String {
+        let passes: Vec<String> = passes.iter().flatten().map(|pass| pass.to_string()).collect();
+        if !interactive || passes.is_empty() {
+            return String::new();
+        }
+        format!(r#" data-pass="{}""#, passes.join(" "))
+    };
 
-    let svg_size: FloatSize = size2(x + margin, max_y + margin);
-    begin_svg(output, &svg_size);
     let bg_rect = FloatRectangle {
         min: point2(0.0, 0.0),
-        max: point2(svg_size.width, svg_size.height),
+        max: point2(layout.size.width, layout.size.height),
     }.inflate(1.0, 1.0);
-    rectangle(output, &bg_rect, 0.0, "fill:rgb(50,50,50)");
+    rectangle(output, &bg_rect, 0.0, &format!("fill:{}", theme.background));
 
-    for rect in &target_rects {
-        rectangle(output, rect, 5.0, "stroke:none;fill:black;fill-opacity:0.2");
+    for target in &layout.targets {
+        let attributes = pass_attribute(&[Some(target.pass)]);
+        rectangle_with_attributes(output, &target.rect, 5.0, &format!("stroke:none;fill:{};fill-opacity:0.2", theme.target_background), &attributes);
     }
 
-    for id in graph.node_ids() {
-        if let Some(rect) = node_label_rects[id.index()] {
-            let pos = rect.min;
-            for input in graph.node_dependencies(id) {
-                let input_pos = node_label_rects[input.index()].unwrap().min;
-                let from = input_pos + vec2(node_width, node_height / 2.0);
-                let to = pos + vec2(0.0, node_height / 2.0);
-                link(output, from + vec2(0.0, 1.0), to + vec2(0.0, 1.0), "stroke:black;stroke-opacity:0.4;stroke-width:3px;");
-                link(output, from, to, "stroke:rgb(100, 100, 100);stroke-width:3px;");
-            }
-        }
+    let shadow_style = format!("stroke:black;stroke-opacity:0.4;stroke-width:{}px;", options.link_width);
+    let link_style = format!("stroke:{};stroke-width:{}px;", theme.link, options.link_width);
+
+    for link in &layout.links {
+        let passes = pass_attribute(&[layout.node_passes[link.from.index()], layout.node_passes[link.to.index()]]);
+        let (shadow_attributes, attributes) = if interactive {
+            (passes.clone(), format!(r#" class="link" data-from="{}" data-to="{}"{}"#, link.from.index(), link.to.index(), passes))
+        } else {
+            (String::new(), String::new())
+        };
+        let shadow: Vec<FloatPoint> = link.points.iter().map(|p| *p + vec2(0.0, 1.0)).collect();
+        routed_link_with_attributes(output, &shadow, &shadow_style, &shadow_attributes);
+        routed_link_with_attributes(output, &link.points, &link_style, &attributes);
     }
 
-    for rect in &node_label_rects {
+    for (idx, rect) in layout.nodes.iter().enumerate() {
         if let Some(rect) = rect {
-            rectangle(output, &rect.translate(&vec2(0.0, 2.0)), 3.0, "stroke:none;fill:black;fill-opacity:0.4");
-            rectangle(output, rect, 3.0, "stroke:none;fill:rgb(200, 200, 200);fill-opacity:0.8");
+            let passes = pass_attribute(&[layout.node_passes[idx]]);
+            let attributes = if interactive {
+                format!(r#" class="node" data-node="{}"{}"#, idx, passes)
+            } else {
+                String::new()
+            };
+            let color = match options.node_colors {
+                NodeColors::Uniform => theme.node.clone(),
+                NodeColors::TargetKind => match graph[crate::node_id(idx)].target_kind {
+                    TargetKind::Color => theme.color_target_node.clone(),
+                    TargetKind::Alpha => theme.alpha_target_node.clone(),
+                },
+                NodeColors::TaskCategory => {
+                    category_color(task_category(&format!("{:?}", graph[crate::node_id(idx)].task_id)))
+                }
+            };
+            rectangle_with_attributes(output, &rect.translate(&vec2(0.0, 2.0)), 3.0, "stroke:none;fill:black;fill-opacity:0.4", &passes);
+            rectangle_with_attributes(output, rect, 3.0, &format!("stroke:none;fill:{};fill-opacity:0.8", color), &attributes);
         }
     }
 
-    for &(ref rect, dest, ref alloc_rects, tex_size) in &texture_info {
-        let atlas_min = rect.min + vec2(0.0, texture_box_height);
+    for target in layout.targets.iter().filter(|_| options.show_atlases) {
+        let rect = &target.label_rect;
+        let tex_size = target.texture_size;
+        let atlas_min = rect.min + vec2(0.0, layout.texture_box_height);
         let scale = tex_size.width / node_width;
         let atlas_rect = FloatRectangle {
             min: atlas_min,
             max: atlas_min + vec2(tex_size.width, tex_size.height) / scale,
         };
+        let passes = pass_attribute(&[Some(target.pass)]);
 
         // Per-texture label.
-        //rectangle(output, &rect.translate(&vec2(0.0, 2.0)), 3.0, "stroke:none;fill:black;fill-opacity:0.4");
-        //rectangle(output, rect, 1.0, "stroke:none;fill:black;fill-opacity:0.6");
         let text_pos = point2((rect.min.x + rect.max.x)/2.0, rect.min.y + 10.0);
-        text(output, &format!("{:?} - {}", dest.unwrap(), tex_size), 5.0, text_pos, "text-anchor:middle;text-align:center;fill:rgb(250,250,250);");
+        let style = format!("text-anchor:middle;text-align:center;fill:{};", theme.texture_label);
+        text_with_attributes(output, &format!("{:?} - {}", target.destination.unwrap(), tex_size), 5.0, text_pos, &style, &passes);
 
         // Atlas.
-        rectangle(output, &atlas_rect, 0.0, "stroke:none;fill:black;fill-opacity:0.5");
-        for rect in alloc_rects {
+        rectangle_with_attributes(output, &atlas_rect, 0.0, &format!("stroke:none;fill:{};fill-opacity:0.5", theme.atlas_background), &passes);
+        let style = format!("stroke:none;fill:{};fill-opacity:0.8", theme.allocation);
+        for &(alloc_output, rect) in &target.allocations {
             let scaled_rect = rect.to_f32() / scale;
-            rectangle(output, &scaled_rect.translate(&atlas_rect.min.to_vector()).inflate(-0.1, -0.1), 0.0, "stroke:none;fill:rgb(50,70,180);fill-opacity:0.8");
+            let attributes = if interactive {
+                format!(r#" class="allocation" data-node="{}" data-output="{}"{}"#, alloc_output.node.index(), alloc_output.index, passes)
+            } else {
+                String::new()
+            };
+            rectangle_with_attributes(output, &scaled_rect.translate(&atlas_rect.min.to_vector()).inflate(-0.1, -0.1), 0.0, &style, &attributes);
         }
     }
 
     for id in graph.node_ids() {
-        if let Some(rect) = node_label_rects[id.index()] {
-            let pos = point2((rect.min.x + rect.max.x)/2.0, rect.min.y + 12.0);
-            let name = format!("{}", names.map(|f| f(id)).unwrap_or(""));
-            let kind = format!("Task: {:?}", graph[id].task_id);
-            let size = format!("{}", graph[id].size);
-            let style = "text-anchor:middle;text-align:center;";
-            text(output, &name, 10.0, pos, style);
-            let style = "text-anchor:middle;text-align:center;fill:rgb(50,50,50)";
-            text(output, &kind, 6.0, pos + vec2(0.0, 12.0), style);
-            text(output, &size, 6.0, pos + vec2(0.0, 22.0), style);
+        if let Some(rect) = layout.nodes[id.index()] {
+            let passes = pass_attribute(&[layout.node_passes[id.index()]]);
+            let font_size = options.font_size;
+            let pos = point2((rect.min.x + rect.max.x)/2.0, rect.min.y + font_size * 1.2);
+            let name = escape(graph.label(id).unwrap_or(""));
+            let style = format!("text-anchor:middle;text-align:center;fill:{}", theme.node_label);
+            text_with_attributes(output, &name, font_size, pos, &style, &passes);
+
+            let style = format!("text-anchor:middle;text-align:center;fill:{}", theme.node_details);
+            let mut offset = font_size * 1.2;
+            if options.show_task_ids {
+                let kind = escape(&format!("Task: {:?}", graph[id].task_id));
+                text_with_attributes(output, &kind, font_size * 0.6, pos + vec2(0.0, offset), &style, &passes);
+                offset += font_size;
+            }
+            if options.show_sizes {
+                let size = format!("{}", graph[id].size);
+                text_with_attributes(output, &size, font_size * 0.6, pos + vec2(0.0, offset), &style, &passes);
+            }
+        }
+    }
+}
+
+/// Write an SVG visualization of the passes and targets of a built graph.
+///
+/// Nodes are named after their label, if any. Labels replace the `names` callback this
+/// function used to take: set them with `GraphBuilder::set_label` instead.
+pub fn dump_svg<T: std::fmt::Debug, U>(
+    output: &mut dyn std::io::Write,
+    graph: &BuiltGraph<T, U>,
+) {
+    dump_svg_with_options(output, graph, &SvgOptions::default());
+}
+
+/// Same as `dump_svg` with a custom style and layout.
+pub fn dump_svg_with_options<T: std::fmt::Debug, U>(
+    output: &mut dyn std::io::Write,
+    graph: &BuiltGraph<T, U>,
+    options: &SvgOptions,
+) {
+    let layout = Layout::new(graph, options);
+    begin_svg(output, &layout.size);
+    write_graph(output, graph, &layout, options, false);
+    end_svg(output);
+}
+
+/// A color to tell the nodes apart.
+fn node_color(node: NodeId) -> String {
+    let hue = (node.index() as f32 * 137.5) % 360.0;
+    format!("hsl({}, 60%, 55%)", hue)
+}
+
+/// Write an SVG showing how the dynamic textures are filled over time.
+///
+/// Each texture is a row with one frame per pass. A frame shows the allocations that
+/// are alive during the pass colored by node, with the ones rendered in the pass
+/// outlined, and the ones that were freed right before the pass ghosted.
+pub fn dump_atlas_timeline<T, U>(
+    output: &mut dyn std::io::Write,
+    graph: &BuiltGraph<T, U>,
+) {
+    let frame_width = 120.0;
+    let label_width = 100.0;
+    let header_height = 20.0;
+    let spacing = 10.0;
+    let margin = 10.0;
+
+    let mut textures: Vec<TextureId> = Vec::new();
+    for record in graph.allocations() {
+        if !textures.contains(&record.texture) {
+            textures.push(record.texture);
+        }
+    }
+    textures.sort_by_key(|texture| texture.index());
+
+    let num_passes = graph.passes().len();
+    let mut rows = Vec::with_capacity(textures.len());
+    let mut y = margin + header_height;
+    for &texture in &textures {
+        let size = graph.texture_size(texture).unwrap().to_f32();
+        let scale = frame_width / size.width;
+        let height = size.height * scale;
+        rows.push((texture, size, scale, y));
+        y += height + spacing;
+    }
+
+    let svg_size: FloatSize = size2(
+        margin * 2.0 + label_width + num_passes as f32 * (frame_width + spacing),
+        y + margin,
+    );
+    begin_svg(output, &svg_size);
+    rectangle(output, &FloatRectangle::from_size(svg_size), 0.0, "fill:rgb(50,50,50)");
+
+    let frame_x = |pass: usize| margin + label_width + pass as f32 * (frame_width + spacing);
+    let style = "text-anchor:middle;text-align:center;fill:rgb(220,220,220);";
+    for pass in 0..num_passes {
+        text(output, &format!("Pass {}", pass), 8.0, point2(frame_x(pass) + frame_width / 2.0, margin + 10.0), style);
+    }
+
+    for &(texture, size, scale, y) in &rows {
+        let style = "fill:rgb(220,220,220);";
+        text(output, &format!("{:?}", texture), 8.0, point2(margin, y + 10.0), style);
+        text(output, &format!("{}x{}", size.width, size.height), 6.0, point2(margin, y + 20.0), style);
+
+        for pass in 0..num_passes {
+            let origin = vec2(frame_x(pass), y);
+            let frame = FloatRectangle::from_size(size * scale).translate(&origin);
+            rectangle(output, &frame, 0.0, "stroke:none;fill:black;fill-opacity:0.5");
+
+            for record in graph.allocations() {
+                if record.texture != texture {
+                    continue;
+                }
+                let rect = (graph.output_rectangle(record.output).to_f32() * scale).translate(&origin);
+                let color = node_color(record.output.node);
+                let alive = record.allocated <= pass && record.deallocated.map(|last| pass < last).unwrap_or(true);
+                if alive {
+                    let outline = if record.allocated == pass {
+                        "stroke:white;stroke-width:1px;"
+                    } else {
+                        "stroke:none;"
+                    };
+                    rectangle(output, &rect.inflate(-0.5, -0.5), 0.0, &format!("{}fill:{};fill-opacity:0.8", outline, color));
+                } else if record.deallocated == Some(pass) {
+                    rectangle(output, &rect.inflate(-0.5, -0.5), 0.0, &format!("stroke:{};stroke-width:0.5px;stroke-dasharray:2,1;fill:{};fill-opacity:0.15", color, color));
+                }
+            }
         }
     }
 
     end_svg(output);
 }
 
+#[test]
+fn svg_options() {
+    use crate::*;
+
+    let mut graph = Graph::new();
+    let n0 = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let n1 = graph.add_node(TaskId::Render(1, 0), TargetKind::Alpha, size2(100, 100), AllocKind::Dynamic, &[n0]);
+    let root = graph.add_node(TaskId::Copy, TargetKind::Color, size2(400, 400), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[n0, n1]);
+    graph.add_root(root);
+
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    let built = graph.build(&BuilderOptions::default(), &mut allocator).unwrap();
+
+    let svg = |options: &SvgOptions| {
+        let mut output = Vec::new();
+        dump_svg_with_options(&mut output, &built, options);
+        String::from_utf8(output).unwrap()
+    };
+
+    let light = svg(&SvgOptions::light());
+    assert!(light.contains("fill:white"));
+    assert!(light.contains("Task: Render(0, 0)"));
+    assert!(!light.contains(r#"class="#));
+
+    let compact = svg(&SvgOptions::compact());
+    assert!(!compact.contains("Task:"));
+    assert!(!compact.contains("100x100"));
+    assert!(!compact.contains("TextureId"));
+    assert!(compact.contains(&SvgTheme::dark().alpha_target_node));
+
+    assert_eq!(task_category("Render(0, 1)"), "Render");
+    assert_eq!(task_category("Copy"), "Copy");
+    let options = SvgOptions { node_colors: NodeColors::TaskCategory, .. SvgOptions::default() };
+    let by_category = svg(&options);
+    assert_eq!(by_category.matches(&category_color("Render")).count(), 2);
+    assert_eq!(by_category.matches(&category_color("Copy")).count(), 1);
+}
+
+#[test]
+fn link_routing() {
+    use crate::*;
+
+    let mut graph = Graph::new();
+    let a = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let b = graph.add_node(TaskId::Render(0, 1), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    // Inputs in the reverse order of the nodes above, the links cross unless the
+    // nodes are reordered.
+    let c = graph.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[b]);
+    let d = graph.add_node(TaskId::Render(0, 3), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[a]);
+    let mut chain = graph.add_node(TaskId::Render(0, 4), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[c, d]);
+    for i in 0..3 {
+        chain = graph.add_node(TaskId::Render(1, i), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[chain]);
+    }
+    // Spans all passes, next to the chain.
+    let root = graph.add_node(TaskId::Render(0, 5), TargetKind::Color, size2(400, 400), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[chain, a, b]);
+    graph.add_root(root);
+
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    let built = graph.build(&BuilderOptions::default(), &mut allocator).unwrap();
+
+    for options in &[SvgOptions::default(), SvgOptions::compact()] {
+        let layout = Layout::new(&built, options);
+        let node_rect = |id: NodeId| layout.nodes[id.index()].unwrap();
+
+        assert!(node_rect(a).center().y < node_rect(b).center().y);
+        assert_eq!(node_rect(a).center().y < node_rect(b).center().y, node_rect(d).center().y < node_rect(c).center().y);
+
+        let mut num_crossings = 0;
+        for link in &layout.links {
+            assert_eq!(*link.points.first().unwrap(), point2(node_rect(link.from).max.x, node_rect(link.from).center().y));
+            assert_eq!(*link.points.last().unwrap(), point2(node_rect(link.to).min.x, node_rect(link.to).center().y));
+            for segment in link.points[1..link.points.len() - 1].chunks(2) {
+                num_crossings += 1;
+                let line = FloatRectangle { min: segment[0], max: segment[1] }.inflate(0.0, 0.5);
+                for rect in layout.nodes.iter().flatten() {
+                    assert!(!line.intersects(rect));
+                }
+            }
+        }
+        // The links from a and b to the root both cross the five passes in between.
+        assert_eq!(num_crossings, 10);
+
+        // They don't overlap until they reach the root.
+        let lanes: Vec<&LinkLayout> = layout.links.iter().filter(|link| link.to == root && link.from != chain).collect();
+        assert_eq!(lanes.len(), 2);
+        for (p0, p1) in lanes[0].points.iter().zip(lanes[1].points.iter()).skip(1).take(10) {
+            assert!((p0.y - p1.y).abs() >= options.link_width * 2.0);
+        }
+    }
+}
+
+#[test]
+fn target_with_only_additional_outputs() {
+    use crate::*;
+
+    // The alpha target of the first pass only holds the additional output of a node
+    // shown in the color target.
+    let mut graph = Graph::new();
+    let a = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let mask = graph.add_output(a, TargetKind::Alpha, size2(50, 50));
+    let root = graph.add_node(TaskId::Render(0, 1), TargetKind::Color, size2(400, 400), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[]);
+    graph.add_output_dependency(root, mask);
+    graph.add_root(root);
+
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    let built = graph.build(&BuilderOptions::default(), &mut allocator).unwrap();
+
+    let layout = Layout::new(&built, &SvgOptions::default());
+    assert_eq!(layout.targets.len(), 3);
+    assert_eq!(layout.links.len(), 1);
+    assert!(layout.nodes[a.index()].is_some());
+
+    dump_svg(&mut Vec::new(), &built);
+    dump_html(&mut Vec::new(), &built);
+}
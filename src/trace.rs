@@ -0,0 +1,177 @@
+use std::fmt::Debug;
+use std::io::Write;
+use crate::graph::*;
+use crate::html::json_string;
+
+/// The duration of a pass in the trace, in microseconds.
+const PASS_DURATION: usize = 1000;
+
+const PID: u32 = 1;
+const PASSES_TID: usize = 0;
+
+/// Name a track and keep the tracks in the order of their ids.
+fn track(output: &mut dyn Write, tid: usize, name: &str) {
+    write!(output,
+        r#",
+    {{ "name": "thread_name", "ph": "M", "pid": {}, "tid": {}, "args": {{ "name": {} }} }},
+    {{ "name": "thread_sort_index", "ph": "M", "pid": {}, "tid": {}, "args": {{ "sort_index": {} }} }}"#,
+        PID, tid, json_string(name),
+        PID, tid, tid,
+    ).unwrap();
+}
+
+fn complete_event(output: &mut dyn Write, name: &str, category: &str, tid: usize, ts: usize, duration: usize, args: &str) {
+    write!(output,
+        r#",
+    {{ "name": {}, "cat": "{}", "ph": "X", "pid": {}, "tid": {}, "ts": {}, "dur": {}, "args": {{ {} }} }}"#,
+        json_string(name), category, PID, tid, ts, duration, args,
+    ).unwrap();
+}
+
+impl<T: Debug, U> BuiltGraph<T, U> {
+    /// Write the built graph in the Chrome trace event format, which can be opened in
+    /// `about:tracing` or Perfetto.
+    ///
+    /// Passes are consecutive time slices of one millisecond. The targets of the passes
+    /// are tracks, one per dynamic target kind and one per fixed texture, and the tasks
+    /// are events that split the time slice of their target. The number of allocated
+    /// pixels of each dynamic texture is shown as a counter.
+    pub fn write_chrome_trace(&self, output: &mut dyn Write) {
+        // All events but the first one start with a comma.
+        write!(output,
+            r#"{{
+  "displayTimeUnit": "ms",
+  "traceEvents": [
+    {{ "name": "process_name", "ph": "M", "pid": {}, "args": {{ "name": "Render graph" }} }}"#,
+            PID,
+        ).unwrap();
+
+        track(output, PASSES_TID, "Passes");
+        for (kind_index, kind) in TARGET_KINDS.iter().enumerate() {
+            track(output, 1 + kind_index, &format!("{:?} targets", kind));
+        }
+
+        // Fixed textures get their own track the first time they are rendered into.
+        let mut fixed_tracks: Vec<TextureId> = Vec::new();
+
+        for (pass_index, pass) in self.passes().iter().enumerate() {
+            let ts = pass_index * PASS_DURATION;
+            let num_tasks: usize = pass.dynamic_targets.iter().chain(pass.fixed_targets.iter()).map(|target| target.tasks.len()).sum();
+            complete_event(output, &format!("Pass {}", pass_index), "pass", PASSES_TID, ts, PASS_DURATION, &format!(r#""tasks": {}"#, num_tasks));
+
+            let dynamic_targets = pass.dynamic_targets.iter()
+                .enumerate()
+                .map(|(kind_index, target)| (1 + kind_index, target));
+            let mut fixed_targets = Vec::with_capacity(pass.fixed_targets.len());
+            for target in &pass.fixed_targets {
+                let texture = target.destination.unwrap();
+                let track = match fixed_tracks.iter().position(|&t| t == texture) {
+                    Some(idx) => idx,
+                    None => {
+                        fixed_tracks.push(texture);
+                        let tid = 1 + NUM_TARGET_KINDS + fixed_tracks.len() - 1;
+                        track(output, tid, &format!("{:?}", texture));
+                        fixed_tracks.len() - 1
+                    }
+                };
+                fixed_targets.push((1 + NUM_TARGET_KINDS + track, target));
+            }
+
+            for (tid, target) in dynamic_targets.chain(fixed_targets) {
+                if target.tasks.is_empty() {
+                    continue;
+                }
+
+                let texture = format!("{:?}", target.destination.unwrap());
+                complete_event(output, &texture, "target", tid, ts, PASS_DURATION, &format!(
+                    r#""pass": {}, "texture": {}, "tasks": {}"#,
+                    pass_index, json_string(&texture), target.tasks.len(),
+                ));
+
+                let task_duration = PASS_DURATION / target.tasks.len();
+                for (task_index, task) in target.tasks.iter().enumerate() {
+                    let node_output = NodeOutput::new(task.node_id, task.output);
+                    let node = &self[task.node_id];
+                    let size = node.output_size(task.output);
+                    let rect = self.output_rectangle(node_output);
+                    let name = match task.output {
+                        0 => self.node_name(task.node_id),
+                        idx => format!("{}.{}", self.node_name(task.node_id), idx),
+                    };
+                    let args = format!(
+                        r#""node": {}, "output": {}, "task": {}, "size": [{}, {}], "rectangle": [{}, {}, {}, {}], "texture": {}"#,
+                        task.node_id.index(),
+                        task.output,
+                        json_string(&format!("{:?}", node.task_id)),
+                        size.width, size.height,
+                        rect.min.x, rect.min.y, rect.max.x, rect.max.y,
+                        json_string(&texture),
+                    );
+                    complete_event(output, &name, "task", tid, ts + task_index * task_duration, task_duration, &args);
+                }
+            }
+        }
+
+        // Counters keep their value until the next event, so the last one goes back to
+        // zero at the end of the graph.
+        let mut textures: Vec<TextureId> = Vec::new();
+        for record in self.allocations() {
+            if !textures.contains(&record.texture) {
+                textures.push(record.texture);
+            }
+        }
+        textures.sort_by_key(|texture| texture.index());
+
+        let num_passes = self.passes().len();
+        for pass_index in 0..=num_passes {
+            let values: Vec<String> = textures.iter().map(|&texture| {
+                let is_live = |record: &&AllocationRecord| {
+                    record.texture == texture
+                        && pass_index < num_passes
+                        && record.allocated <= pass_index
+                        && record.deallocated.map(|pass| pass_index < pass).unwrap_or(true)
+                };
+                let pixels: i32 = self.allocations().iter()
+                    .filter(is_live)
+                    .map(|record| self.output_rectangle(record.output).area())
+                    .sum();
+                format!("{}: {}", json_string(&format!("{:?}", texture)), pixels)
+            }).collect();
+            write!(output,
+                r#",
+    {{ "name": "Allocated pixels", "ph": "C", "pid": {}, "ts": {}, "args": {{ {} }} }}"#,
+                PID, pass_index * PASS_DURATION, values.join(", "),
+            ).unwrap();
+        }
+
+        writeln!(output, "\n  ]\n}}").unwrap();
+    }
+}
+
+#[test]
+fn chrome_trace_output() {
+    use crate::{GuillotineAllocator, size2, point2};
+
+    let mut graph = Graph::new();
+    let n0 = graph.add_node(TaskId::Render(0, 0), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[]);
+    let n1 = graph.add_node(TaskId::Render(0, 1), TargetKind::Alpha, size2(50, 50), AllocKind::Dynamic, &[n0]);
+    let n2 = graph.add_node(TaskId::Render(0, 2), TargetKind::Color, size2(100, 100), AllocKind::Dynamic, &[n0, n1]);
+    let root = graph.add_node(TaskId::Render(0, 3), TargetKind::Color, size2(400, 400), AllocKind::Fixed(TextureId(0), point2(0, 0)), &[n2]);
+    graph.set_label(root, "\"root\"".to_string());
+    graph.add_root(root);
+
+    let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+    let built = graph.build(&BuilderOptions::default(), &mut allocator).unwrap();
+
+    let mut output = Vec::new();
+    built.write_chrome_trace(&mut output);
+    let trace = String::from_utf8(output).unwrap();
+
+    assert!(trace.contains(r#"{ "name": "thread_name", "ph": "M", "pid": 1, "tid": 3, "args": { "name": "TextureId(0)" } }"#));
+    assert!(trace.contains(r#"{ "name": "Pass 3", "cat": "pass", "ph": "X", "pid": 1, "tid": 0, "ts": 3000, "dur": 1000, "args": { "tasks": 1 } }"#));
+    assert!(trace.contains(r#"{ "name": "\"root\"", "cat": "task", "ph": "X", "pid": 1, "tid": 3, "ts": 3000, "dur": 1000, "args": { "node": 3, "output": 0, "task": "Render(0, 3)", "size": [400, 400], "rectangle": [0, 0, 400, 400], "texture": "TextureId(0)" } }"#));
+    assert!(trace.contains(r#""name": "NodeId(1)", "cat": "task", "ph": "X", "pid": 1, "tid": 2, "ts": 1000"#));
+    // Nothing is allocated after the last pass.
+    assert!(trace.contains(r#"{ "name": "Allocated pixels", "ph": "C", "pid": 1, "ts": 4000, "args": { "TextureId(dyn 0)": 0, "TextureId(dyn 1)": 0, "TextureId(dyn 2)": 0 } }"#));
+    assert_eq!(trace.matches('{').count(), trace.matches('}').count());
+}
@@ -0,0 +1,371 @@
+//! Property tests building random graphs with every combination of builder options and
+//! checking the invariants of the built graphs.
+
+use rendergraph::*;
+use std::collections::HashMap;
+
+/// Xorshift random number generator, so that failures can be reproduced from a seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+
+        x
+    }
+
+    /// A value in `min..=max`.
+    fn range(&mut self, min: u32, max: u32) -> u32 {
+        min + (self.next() % (max - min + 1) as u64) as u32
+    }
+
+    fn chance(&mut self, probability: f32) -> bool {
+        (self.next() % 1000) < (probability * 1000.0) as u64
+    }
+}
+
+#[derive(Clone, Debug)]
+struct GraphConfig {
+    /// Number of layers, nodes depend on nodes of previous layers.
+    depth: u32,
+    max_nodes_per_layer: u32,
+    max_fan_in: u32,
+    max_size: u32,
+    /// Probability of a node being much larger than `max_size`.
+    large_ratio: f32,
+    /// Probability of a node rendering into its own fixed texture.
+    fixed_ratio: f32,
+    /// Probability of a node rendering into an alpha target.
+    alpha_ratio: f32,
+    /// Probability of a node of the intermediate layers being a root. Nodes of the last
+    /// layer are always roots.
+    root_ratio: f32,
+    /// Probability of a node of the intermediate layers being a side effect.
+    side_effect_ratio: f32,
+    /// Probability of a node having an additional output.
+    extra_output_ratio: f32,
+    /// Number of external inputs, each in its own texture.
+    num_external_inputs: u32,
+    /// Probability of a node rendering in place into a node of a previous layer.
+    in_place_ratio: f32,
+    /// Probability of a node having random allocation options.
+    options_ratio: f32,
+}
+
+const CONFIGS: &[GraphConfig] = &[
+    GraphConfig {
+        depth: 4, max_nodes_per_layer: 3, max_fan_in: 2, max_size: 200, large_ratio: 0.0, fixed_ratio: 0.1, alpha_ratio: 0.3, root_ratio: 0.1,
+        side_effect_ratio: 0.0, extra_output_ratio: 0.0, num_external_inputs: 0, in_place_ratio: 0.0, options_ratio: 0.0,
+    },
+    GraphConfig {
+        depth: 3, max_nodes_per_layer: 12, max_fan_in: 4, max_size: 300, large_ratio: 0.1, fixed_ratio: 0.1, alpha_ratio: 0.3, root_ratio: 0.1,
+        side_effect_ratio: 0.05, extra_output_ratio: 0.1, num_external_inputs: 1, in_place_ratio: 0.0, options_ratio: 0.2,
+    },
+    GraphConfig {
+        depth: 12, max_nodes_per_layer: 3, max_fan_in: 3, max_size: 100, large_ratio: 0.05, fixed_ratio: 0.1, alpha_ratio: 0.5, root_ratio: 0.05,
+        side_effect_ratio: 0.05, extra_output_ratio: 0.1, num_external_inputs: 2, in_place_ratio: 0.1, options_ratio: 0.2,
+    },
+    GraphConfig {
+        depth: 6, max_nodes_per_layer: 6, max_fan_in: 3, max_size: 400, large_ratio: 0.2, fixed_ratio: 0.4, alpha_ratio: 0.5, root_ratio: 0.2,
+        side_effect_ratio: 0.1, extra_output_ratio: 0.2, num_external_inputs: 1, in_place_ratio: 0.1, options_ratio: 0.3,
+    },
+    GraphConfig {
+        depth: 8, max_nodes_per_layer: 5, max_fan_in: 3, max_size: 200, large_ratio: 0.1, fixed_ratio: 0.1, alpha_ratio: 0.3, root_ratio: 0.1,
+        side_effect_ratio: 0.1, extra_output_ratio: 0.3, num_external_inputs: 3, in_place_ratio: 0.3, options_ratio: 0.5,
+    },
+];
+
+const SEEDS_PER_CONFIG: u64 = 100;
+
+fn random_options(rng: &mut Rng) -> AllocationOptions {
+    AllocationOptions {
+        padding: rng.range(0, 3) as i32,
+        border: rng.range(0, 2) as i32,
+        alignment: [0, 1, 2, 4, 8][rng.range(0, 4) as usize],
+    }
+}
+
+fn generate_graph(config: &GraphConfig, rng: &mut Rng) -> Graph {
+    let mut graph = Graph::new();
+    // The outputs later nodes can read.
+    let mut previous_outputs = Vec::new();
+    let mut next_fixed_texture = 0;
+    // Nodes that something reads from, which in-place nodes can't render into.
+    let mut read = Vec::new();
+    let mut roots = Vec::new();
+
+    for i in 0..config.num_external_inputs {
+        let size = size2(rng.range(1, config.max_size) as i32, rng.range(1, config.max_size) as i32);
+        let origin = point2(rng.range(0, 100) as i32, rng.range(0, 100) as i32);
+        let id = graph.add_node(TaskId::Render(u16::MAX, i), TargetKind::Color, size, AllocKind::External(TextureId(1000 + i as u64), origin), &[]);
+        previous_outputs.push(NodeOutput::main(id));
+        read.push(false);
+    }
+
+    for layer in 0..config.depth {
+        let last_layer = layer + 1 == config.depth;
+        let mut layer_outputs = Vec::new();
+        for _ in 0..rng.range(1, config.max_nodes_per_layer) {
+            let task_id = TaskId::Render(layer as u16, layer_outputs.len() as u32);
+            let is_root = last_layer || rng.chance(config.root_ratio);
+
+            // In-place nodes only read the node they render into, which nothing else
+            // reads afterwards.
+            if !last_layer && rng.chance(config.in_place_ratio) {
+                let candidates: Vec<usize> = previous_outputs.iter().enumerate()
+                    .filter(|&(_, output)| {
+                        output.index == 0
+                            && !read[output.node.index()]
+                            && !roots.contains(&output.node)
+                            && !graph[output.node].is_external()
+                    })
+                    .map(|(idx, _)| idx)
+                    .collect();
+                if !candidates.is_empty() {
+                    let dep = previous_outputs.remove(candidates[rng.range(0, candidates.len() as u32 - 1) as usize]).node;
+                    read[dep.index()] = true;
+                    let (target_kind, size, options) = (graph[dep].target_kind, graph[dep].size, graph[dep].alloc_options);
+                    let id = graph.add_node(task_id, target_kind, size, AllocKind::InPlace(dep), &[dep]);
+                    graph.set_allocation_options(id, options);
+                    read.push(false);
+                    if is_root {
+                        graph.add_root(id);
+                        roots.push(id);
+                    }
+                    layer_outputs.push(NodeOutput::main(id));
+                    continue;
+                }
+            }
+
+            let target_kind = if rng.chance(config.alpha_ratio) { TargetKind::Alpha } else { TargetKind::Color };
+            let max_size = if rng.chance(config.large_ratio) { config.max_size * 4 } else { config.max_size };
+            let size = size2(rng.range(1, max_size) as i32, rng.range(1, max_size) as i32);
+
+            let alloc_kind = if last_layer || rng.chance(config.fixed_ratio) {
+                next_fixed_texture += 1;
+                AllocKind::Fixed(TextureId(next_fixed_texture), point2(rng.range(0, 100) as i32, rng.range(0, 100) as i32))
+            } else {
+                AllocKind::Dynamic
+            };
+
+            let mut inputs = Vec::new();
+            if !previous_outputs.is_empty() {
+                for _ in 0..rng.range(0, config.max_fan_in) {
+                    let input = previous_outputs[rng.range(0, previous_outputs.len() as u32 - 1) as usize];
+                    if !inputs.contains(&input) {
+                        inputs.push(input);
+                    }
+                }
+            }
+
+            let id = graph.add_node(task_id, target_kind, size, alloc_kind, &[]);
+            read.push(false);
+            for &input in &inputs {
+                graph.add_output_dependency(id, input);
+                read[input.node.index()] = true;
+            }
+            if rng.chance(config.options_ratio) {
+                graph.set_allocation_options(id, random_options(rng));
+            }
+            layer_outputs.push(NodeOutput::main(id));
+            if rng.chance(config.extra_output_ratio) {
+                let kind = if rng.chance(0.5) { TargetKind::Alpha } else { TargetKind::Color };
+                let size = size2(rng.range(1, config.max_size) as i32, rng.range(1, config.max_size) as i32);
+                layer_outputs.push(graph.add_output(id, kind, size));
+            }
+
+            if is_root {
+                graph.add_root(id);
+                roots.push(id);
+            } else if rng.chance(config.side_effect_ratio) {
+                graph.add_side_effect(id);
+            }
+        }
+        previous_outputs.extend_from_slice(&layer_outputs);
+    }
+
+    graph
+}
+
+/// The texture an output is read from.
+fn input_texture(built: &BuiltGraph, output_textures: &HashMap<NodeOutput, TextureId>, input: NodeOutput) -> TextureId {
+    match built[input.node].alloc_kind {
+        AllocKind::External(texture, _) => texture,
+        _ => output_textures[&input],
+    }
+}
+
+fn check_invariants(graph: &Graph, built: &BuiltGraph) -> Result<(), String> {
+    let mut node_passes = HashMap::new();
+    let mut output_textures = HashMap::new();
+    for (pass_index, pass) in built.passes().iter().enumerate() {
+        for target in pass.dynamic_targets.iter().chain(pass.fixed_targets.iter()) {
+            if target.tasks().is_empty() {
+                continue;
+            }
+            let destination = target.destination().ok_or("Target without a destination")?;
+            for task in target.tasks() {
+                let output = NodeOutput::new(task.node_id, task.output);
+                if output_textures.insert(output, destination).is_some() {
+                    return Err(format!("{:?} is rendered twice", output));
+                }
+                node_passes.insert(task.node_id, pass_index);
+            }
+        }
+    }
+
+    for &root in graph.roots().iter().chain(graph.side_effects().iter()) {
+        if !node_passes.contains_key(&root) {
+            return Err(format!("{:?} is a root or a side effect but is not rendered", root));
+        }
+    }
+
+    // Dependencies are rendered in earlier passes.
+    for (&node, &pass) in &node_passes {
+        for input in built.node_inputs(node) {
+            if built[input.node].is_external() {
+                continue;
+            }
+            match node_passes.get(&input.node) {
+                Some(&input_pass) if input_pass < pass => {}
+                _ => return Err(format!("{:?} in pass {} reads {:?} which isn't rendered before", node, pass, input)),
+            }
+        }
+    }
+
+    // No pass reads from a texture it renders into, apart from in-place nodes reading
+    // the node they render into.
+    for (pass_index, pass) in built.passes().iter().enumerate() {
+        let mut written = Vec::new();
+        let mut reads = Vec::new();
+        for target in pass.dynamic_targets.iter().chain(pass.fixed_targets.iter()) {
+            if target.tasks().is_empty() {
+                continue;
+            }
+            written.push(target.destination().unwrap());
+            for task in target.tasks() {
+                for input in built.node_inputs(task.node_id) {
+                    if built[task.node_id].alloc_kind == AllocKind::InPlace(input.node) && input.index == 0 {
+                        continue;
+                    }
+                    reads.push((task.node_id, input));
+                }
+            }
+        }
+        for (node, input) in reads {
+            let texture = input_texture(built, &output_textures, input);
+            if written.contains(&texture) {
+                return Err(format!("{:?} in pass {} reads {:?} from {:?} which the pass renders into", node, pass_index, input, texture));
+            }
+        }
+    }
+
+    let records = built.allocations();
+    for record in records {
+        if output_textures.get(&record.output) != Some(&record.texture) {
+            return Err(format!("{:?} is not allocated in the texture it is rendered into", record));
+        }
+
+        // Allocations are inside of their texture.
+        let rect = built.output_rectangle(record.output);
+        let size = built.texture_size(record.texture).ok_or("Dynamic texture without a size")?;
+        if rect.min.x < 0 || rect.min.y < 0 || rect.max.x > size.width || rect.max.y > size.height {
+            return Err(format!("{:?} of {:?} is outside of the texture ({:?})", rect, record.output, size));
+        }
+
+        // The allocations of roots, including the ones in-place roots render into, are
+        // never deallocated.
+        let is_root = graph.roots().iter().any(|&root| built.in_place_owner(root) == record.output.node);
+        match record.deallocated {
+            Some(_) if is_root => return Err(format!("{:?} of a root is deallocated", record)),
+            Some(deallocated) if deallocated > built.passes().len() => {
+                return Err(format!("{:?} is deallocated after the end of the graph", record));
+            }
+            _ => {}
+        }
+    }
+
+    // Allocations that are alive at the same time don't overlap.
+    let end = |record: &AllocationRecord| record.deallocated.unwrap_or(usize::MAX);
+    for (idx, a) in records.iter().enumerate() {
+        for b in &records[idx + 1..] {
+            let live_together = a.allocated < end(b) && b.allocated < end(a);
+            if a.texture == b.texture && live_together && built.output_rectangle(a.output).intersects(built.output_rectangle(b.output)) {
+                return Err(format!("{:?} and {:?} overlap", a, b));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn builder_options() -> Vec<BuilderOptions> {
+    let mut options = Vec::new();
+    for &targets in &[TargetOptions::Direct, TargetOptions::PingPong] {
+        options.push(BuilderOptions { targets, allocations: AllocationStrategy::PassByPass, deallocate_before_allocate: false });
+        options.push(BuilderOptions { targets, allocations: AllocationStrategy::PassByPass, deallocate_before_allocate: true });
+        options.push(BuilderOptions { targets, allocations: AllocationStrategy::Planned, deallocate_before_allocate: false });
+    }
+
+    options
+}
+
+#[test]
+fn random_graphs() {
+    let mut num_in_place_graphs = 0;
+    let mut num_in_place_conflicts = 0;
+    for (config_index, config) in CONFIGS.iter().enumerate() {
+        for seed in 0..SEEDS_PER_CONFIG {
+            let graph = generate_graph(config, &mut Rng::new(seed));
+            let has_in_place_nodes = graph.node_ids().any(|id| matches!(graph[id].alloc_kind, AllocKind::InPlace(..)));
+            for options in builder_options() {
+                let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+                let result = graph.clone().build(&options, &mut allocator);
+                if has_in_place_nodes {
+                    num_in_place_graphs += 1;
+                    // Render targets are chosen without avoiding the textures in-place
+                    // nodes render into, the conflicts are reported instead.
+                    if let Err(BuildError::InPlaceConflict { .. }) = result {
+                        num_in_place_conflicts += 1;
+                        continue;
+                    }
+                }
+                let result = result
+                    .map_err(|error| error.to_string())
+                    .and_then(|built| check_invariants(&graph, &built));
+                if let Err(message) = result {
+                    panic!("config {} ({:?}), seed {}, {:?}: {}", config_index, config, seed, options, message);
+                }
+            }
+        }
+    }
+
+    // A good part of the graphs with in-place nodes are built.
+    assert!(num_in_place_graphs > 0);
+    assert!(num_in_place_conflicts * 3 < num_in_place_graphs * 2, "{} conflicts out of {}", num_in_place_conflicts, num_in_place_graphs);
+}
+
+#[test]
+fn generator_is_deterministic() {
+    let a = generate_graph(&CONFIGS[4], &mut Rng::new(42));
+    let b = generate_graph(&CONFIGS[4], &mut Rng::new(42));
+    assert_eq!(a.num_nodes(), b.num_nodes());
+    assert_eq!(a.roots(), b.roots());
+    assert_eq!(a.side_effects(), b.side_effects());
+    for id in a.node_ids() {
+        assert_eq!(a.node_inputs(id).collect::<Vec<_>>(), b.node_inputs(id).collect::<Vec<_>>());
+        assert_eq!(a[id].size, b[id].size);
+        assert_eq!(a[id].alloc_kind, b[id].alloc_kind);
+        assert_eq!(a[id].alloc_options, b[id].alloc_options);
+        assert_eq!(a[id].num_outputs(), b[id].num_outputs());
+    }
+}
+
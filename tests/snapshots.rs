@@ -0,0 +1,157 @@
+//! Snapshot tests of built graphs.
+//!
+//! Each fixture in `tests/fixtures` describes a graph, one statement per line:
+//!
+//! ```text
+//! # Comment
+//! node <name> <Color|Alpha> <width>x<height> [fixed <texture> <x>,<y>] [<- <dependency>...]
+//! root <name>
+//! ```
+//!
+//! The graph is built with each target option, with and without deallocations, and the
+//! compact form of the built graphs is compared to `tests/snapshots/<fixture>.snap`.
+//! Run the tests with `UPDATE_SNAPSHOTS=1` to write the snapshots instead, and review
+//! the changes before checking them in.
+
+use rendergraph::*;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+fn parse_fixture(text: &str) -> (Graph, Vec<String>) {
+    let mut graph = Graph::new();
+    let mut names = Vec::new();
+    let mut ids: HashMap<&str, NodeId> = HashMap::new();
+
+    for (line_index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message: &str| -> ! { panic!("line {}: {}: {:?}", line_index + 1, message, line) };
+
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("node") => {
+                let name = words.next().unwrap_or_else(|| error("missing name"));
+                let target_kind = match words.next() {
+                    Some("Color") => TargetKind::Color,
+                    Some("Alpha") => TargetKind::Alpha,
+                    _ => error("expected a target kind"),
+                };
+                let size = parse_pair(words.next(), 'x').unwrap_or_else(|| error("expected a size"));
+
+                let mut alloc_kind = AllocKind::Dynamic;
+                let mut deps = Vec::new();
+                while let Some(word) = words.next() {
+                    match word {
+                        "fixed" => {
+                            let texture = words.next().and_then(|w| w.parse().ok()).unwrap_or_else(|| error("expected a texture id"));
+                            let origin = parse_pair(words.next(), ',').unwrap_or_else(|| error("expected a position"));
+                            alloc_kind = AllocKind::Fixed(TextureId(texture), point2(origin.0, origin.1));
+                        }
+                        "<-" => {
+                            for dep in words.by_ref() {
+                                deps.push(*ids.get(dep).unwrap_or_else(|| error("unknown dependency")));
+                            }
+                        }
+                        _ => error("unexpected word"),
+                    }
+                }
+
+                let task_id = TaskId::Render(0, names.len() as u32);
+                let id = graph.add_node(task_id, target_kind, size2(size.0, size.1), alloc_kind, &deps);
+                graph.set_label(id, name.to_string());
+                ids.insert(name, id);
+                names.push(name.to_string());
+            }
+            Some("root") => {
+                let name = words.next().unwrap_or_else(|| error("missing name"));
+                graph.add_root(*ids.get(name).unwrap_or_else(|| error("unknown node")));
+            }
+            _ => error("expected node or root"),
+        }
+    }
+
+    (graph, names)
+}
+
+fn parse_pair(word: Option<&str>, separator: char) -> Option<(i32, i32)> {
+    let mut values = word?.split(separator).map(|value| value.parse().ok());
+    match (values.next(), values.next(), values.next()) {
+        (Some(Some(a)), Some(Some(b)), None) => Some((a, b)),
+        _ => None,
+    }
+}
+
+/// Build the graph of a fixture in each configuration and print the results.
+fn render_snapshot(fixture: &str) -> String {
+    let (graph, names) = parse_fixture(fixture);
+
+    let mut snapshot = String::new();
+    for (idx, name) in names.iter().enumerate() {
+        snapshot.push_str(&format!("n{}: {}\n", idx, name));
+    }
+
+    for &with_deallocations in &[false, true] {
+        for &targets in &[TargetOptions::Direct, TargetOptions::PingPong] {
+            let options = BuilderOptions { targets, .. BuilderOptions::default() };
+            let mut allocator = GuillotineAllocator::new(size2(1024, 1024));
+            let mut allocator = DbgTextureAllocator::new(&mut allocator);
+            allocator.record_deallocations = with_deallocations;
+            let built = graph.clone().build(&options, &mut allocator).unwrap();
+
+            snapshot.push_str(&format!("\n## targets: {:?}, deallocations: {:?}\n", targets, with_deallocations));
+            snapshot.push_str(&built.compact().to_string());
+        }
+    }
+
+    snapshot
+}
+
+fn check_snapshot(name: &str) {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let fixture_path = root.join("fixtures").join(format!("{}.graph", name));
+    let snapshot_path = root.join("snapshots").join(format!("{}.snap", name));
+
+    let fixture = fs::read_to_string(&fixture_path).expect("Failed to read the fixture");
+    let actual = render_snapshot(&fixture);
+
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        fs::create_dir_all(snapshot_path.parent().unwrap()).unwrap();
+        fs::write(&snapshot_path, &actual).expect("Failed to write the snapshot");
+        return;
+    }
+
+    let expected = fs::read_to_string(&snapshot_path).unwrap_or_else(|_| {
+        panic!("Missing snapshot {:?}, run the tests with UPDATE_SNAPSHOTS=1 to create it.\n{}", snapshot_path, actual)
+    });
+    if expected == actual {
+        return;
+    }
+
+    let mut diff = String::new();
+    let expected_lines: Vec<&str> = expected.lines().collect();
+    let actual_lines: Vec<&str> = actual.lines().collect();
+    for idx in 0..expected_lines.len().max(actual_lines.len()) {
+        let expected_line = expected_lines.get(idx);
+        let actual_line = actual_lines.get(idx);
+        if expected_line != actual_line {
+            diff.push_str(&format!("line {}:\n  - {}\n  + {}\n", idx + 1, expected_line.unwrap_or(&""), actual_line.unwrap_or(&"")));
+        }
+    }
+    panic!(
+        "Snapshot {:?} doesn't match, run the tests with UPDATE_SNAPSHOTS=1 to update it.\n{}",
+        snapshot_path, diff,
+    );
+}
+
+#[test]
+fn simple_graph() {
+    check_snapshot("simple_graph");
+}
+
+#[test]
+fn stacked_shadows() {
+    check_snapshot("stacked_shadows");
+}